@@ -1,8 +1,16 @@
-use {Error, ResultExt};
-use backend::Backend;
+use {Error, ErrorKind, ResultExt};
+use backend::{Backend, RepositoryStatus};
 
 use git2::{self, Repository, Direction, AutotagOption};
-use std::path::Path;
+use git2::build::CheckoutBuilder;
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The mode of index entries for submodules.
+const GITLINK_MODE: u32 = 0o160000;
 
 pub struct Git {
     /// The repository.
@@ -14,7 +22,7 @@ impl Git {
         let repo = Repository::open(repo_path).
             chain_err(|| format!("could not open '{}'", repo_path.display()))?;
 
-        Ok(Git { repo: repo })
+        Ok(Git { repo })
     }
 
     pub fn setup(dest: &Path, source: &str) -> Result<Git, Error> {
@@ -22,7 +30,7 @@ impl Git {
         let repo = Repository::clone_recurse(source, dest).chain_err(|| format!("could not clone '{}'", source))?;
         ilog!("successfully cloned Git repository");
 
-        Ok(Git { repo: repo })
+        Ok(Git { repo })
     }
 
     pub fn open_or_create(dest: &Path, source: &str) -> Result<Git, Error> {
@@ -33,6 +41,11 @@ impl Git {
         }
     }
 
+    /// Gets the path of the working tree, or of the repository itself if it is bare.
+    fn path(&self) -> &Path {
+        self.repo.workdir().unwrap_or_else(|| self.repo.path())
+    }
+
     fn is_worktree_dirty(&self) -> Result<bool, Error> {
         Ok(self.repo.statuses(None)?.
             iter().
@@ -43,20 +56,17 @@ impl Git {
 impl Backend for Git {
     fn update(&mut self, _verbose: bool) -> Result<(), Error> {
         if self.is_worktree_dirty()? {
-            fatal!("dotfiles repository needs to have a clean worktree ({})",
-                   self.repo.path().display());
+            bail!(ErrorKind::DirtyWorktree(self.path().to_owned()));
         }
 
-        self::ensure_head_is_named_reference(&mut self.repo)?;
+        self::ensure_head_is_named_reference(&self.repo)?;
         let mut original_head = self.repo.head()?;
-        assert!(original_head.is_branch(), "HEAD is not a branch");
 
-        let branch_name = original_head.shorthand().unwrap().to_owned();
+        let branch_name = original_head.shorthand().ok_or("branch name is not valid UTF-8")?.to_owned();
 
-        let remote_name = if let Some(name) = self.repo.remotes()?.iter().next() {
-            name.expect("branch name is not valid utf-8").to_owned()
-        } else {
-            return Err("repository has no remotes set up".into());
+        let remote_name = match self.repo.remotes()?.iter().next() {
+            Some(name) => name.ok_or("remote name is not valid UTF-8")?.to_owned(),
+            None => bail!(ErrorKind::NoRemote(self.path().to_owned())),
         };
 
         let mut remote = self.repo.find_remote(&remote_name)?;
@@ -74,9 +84,10 @@ impl Backend for Git {
 
         let remote_ref_name = format!("refs/remotes/{}/{}", remote_name, branch_name);
         let remote_ref = self.repo.find_reference(&remote_ref_name)?;
-        let current_head = original_head.set_target(remote_ref.target().unwrap(), "updating branch for new dotfiles")?;
-        let original_oid = original_head.target().unwrap();
-        let current_oid = current_head.target().unwrap();
+        let remote_oid = remote_ref.target().ok_or_else(|| format!("'{}' is a symbolic reference", remote_ref_name))?;
+        let current_head = original_head.set_target(remote_oid, "updating branch for new dotfiles")?;
+        let original_oid = original_head.target().ok_or("HEAD is a symbolic reference")?;
+        let current_oid = remote_oid;
 
         let current_oid_label = &current_oid.to_string()[0..7];
         if original_head == current_head {
@@ -97,7 +108,7 @@ impl Backend for Git {
                 let commit = self.repo.find_commit(oid)?;
                 let oid_label = &oid.to_string()[..7];
 
-                ilog!("{} {}", oid_label, commit.message().unwrap().trim());
+                ilog!("{} {}", oid_label, commit.message().unwrap_or("").trim());
             }
 
             ilog!("");
@@ -107,21 +118,208 @@ impl Backend for Git {
 
         Ok(())
     }
+
+    fn checkout(&mut self, reference: Option<&str>) -> Result<(), Error> {
+        if self.is_worktree_dirty()? {
+            bail!(ErrorKind::DirtyWorktree(self.path().to_owned()));
+        }
+
+        let mut options = CheckoutBuilder::new();
+        options.force();
+
+        match reference {
+            Some(reference) => {
+                let commit = self::find_commit(&self.repo, reference)?;
+
+                self.repo.checkout_tree(commit.as_object(), Some(&mut options))?;
+                self.repo.set_head_detached(commit.id())?;
+                ilog!("checked out {} at {}", reference, &commit.id().to_string()[..7]);
+            },
+            None => {
+                let branch_name = self::default_branch(&self.repo)?;
+
+                self.repo.set_head(&format!("refs/heads/{}", branch_name))?;
+                self.repo.checkout_head(Some(&mut options))?;
+                ilog!("checked out {}", branch_name);
+            },
+        }
+
+        Ok(())
+    }
+
+    fn revision(&self) -> Result<Option<String>, Error> {
+        match self.repo.head() {
+            Ok(head) => Ok(head.target().map(|oid| oid.to_string())),
+            // A freshly initialized repository has no commits.
+            Err(ref e) if e.code() == git2::ErrorCode::UnbornBranch => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn status(&self) -> Result<RepositoryStatus, Error> {
+        let mut options = git2::StatusOptions::new();
+        options.include_untracked(true).recurse_untracked_dirs(true);
+
+        let dirty = self.repo.statuses(Some(&mut options))?.iter().
+            filter(|entry| !entry.status().is_empty() && !entry.status().contains(git2::Status::IGNORED)).
+            filter_map(|entry| entry.path().map(PathBuf::from)).
+            collect();
+
+        let (branch, ahead_behind) = match self.repo.head() {
+            Ok(head) => if head.is_branch() {
+                let name = head.shorthand().map(ToOwned::to_owned);
+                let branch = git2::Branch::wrap(head);
+
+                // Branches don't need to track anything.
+                let ahead_behind = match (branch.get().target(), branch.upstream().ok().and_then(|u| u.get().target())) {
+                    (Some(local), Some(upstream)) => Some(self.repo.graph_ahead_behind(local, upstream)?),
+                    _ => None,
+                };
+
+                (name, ahead_behind)
+            } else {
+                (None, None)
+            },
+            Err(ref e) if e.code() == git2::ErrorCode::UnbornBranch => (None, None),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(RepositoryStatus { revision: self.revision()?, branch, dirty, ahead_behind })
+    }
+
+    fn diagnose(&self) -> Result<Vec<String>, Error> {
+        let mut problems = Vec::new();
+        let remotes = self.repo.remotes()?;
+
+        if remotes.is_empty() {
+            problems.push("the repository has no remotes, so it cannot be updated".to_owned());
+        }
+
+        for name in remotes.iter().flatten() {
+            let remote = self.repo.find_remote(name)?;
+
+            match remote.url() {
+                None => problems.push(format!("remote '{}' has no URL", name)),
+                // Remotes on this machine can at least be checked without the network.
+                Some(url) => {
+                    let local_path = url.strip_prefix("file://").unwrap_or(url);
+
+                    if local_path.starts_with('/') && !Path::new(local_path).exists() {
+                        problems.push(format!("remote '{}' points to '{}', which does not exist", name, local_path));
+                    }
+                },
+            }
+        }
+
+        match self.repo.head() {
+            Ok(ref head) if head.is_branch() => (),
+            Ok(..) => problems.push("HEAD is not a branch, so the repository cannot be updated".to_owned()),
+            Err(ref e) if e.code() == git2::ErrorCode::UnbornBranch => {
+                problems.push("the repository has no commits".to_owned());
+            },
+            Err(e) => problems.push(format!("HEAD cannot be read: {}", e)),
+        }
+
+        Ok(problems)
+    }
+
+    fn compact(&self, verbose: bool) -> Result<(), Error> {
+        // libgit2 cannot repack repositories, so this needs Git itself.
+        let mut command = Command::new("git");
+        command.arg("gc").current_dir(self.repo.path());
+
+        if !verbose {
+            command.arg("--quiet");
+        }
+
+        let status = command.status().chain_err(|| "could not run 'git gc', is Git installed?")?;
+
+        if !status.success() {
+            bail!("'git gc' failed in '{}' ({})", self.repo.path().display(), status);
+        }
+
+        Ok(())
+    }
+
+    fn managed_files(&self) -> Result<Option<BTreeSet<PathBuf>>, Error> {
+        // The index rather than HEAD, so that files are linked as soon as they are staged.
+        let files = self.repo.index()?.iter().
+            // Submodules are entries of their own, but they are never dotfiles.
+            filter(|entry| entry.mode != GITLINK_MODE).
+            map(|entry| PathBuf::from(OsStr::from_bytes(&entry.path))).
+            collect();
+
+        Ok(Some(files))
+    }
+
+    fn submodules(&self) -> Result<Vec<PathBuf>, Error> {
+        Ok(self.repo.submodules()?.iter().map(|submodule| submodule.path().to_owned()).collect())
+    }
+
+    fn stage(&self, path: &Path) -> Result<(), Error> {
+        let mut index = self.repo.index()?;
+        index.add_path(path)?;
+        index.write()?;
+        Ok(())
+    }
 }
 
-fn ensure_head_is_named_reference(repo: &mut Repository) -> Result<(), git2::Error> {
+/// Finds the commit a branch, tag or commit refers to.
+///
+/// Branches which only exist on a remote are found too.
+fn find_commit<'a>(repo: &'a Repository, reference: &str) -> Result<git2::Commit<'a>, Error> {
+    let mut candidates = vec![reference.to_owned()];
+    candidates.extend(repo.remotes()?.iter().flatten().map(|remote| format!("{}/{}", remote, reference)));
+
+    for candidate in candidates {
+        if let Ok(object) = repo.revparse_single(&candidate) {
+            return Ok(object.peel_to_commit()?);
+        }
+    }
+
+    bail!("could not find '{}' in the dotfiles repository", reference)
+}
+
+/// Gets the name of the branch that was checked out when the repository was cloned.
+fn default_branch(repo: &Repository) -> Result<String, Error> {
+    for remote in repo.remotes()?.iter().flatten() {
+        let head = match repo.find_reference(&format!("refs/remotes/{}/HEAD", remote)) {
+            Ok(head) => head,
+            Err(..) => continue,
+        };
+
+        let prefix = format!("refs/remotes/{}/", remote);
+        if let Some(branch_name) = head.symbolic_target().and_then(|target| target.strip_prefix(&prefix)) {
+            if repo.find_branch(branch_name, git2::BranchType::Local).is_ok() {
+                return Ok(branch_name.to_owned());
+            }
+        }
+    }
+
+    // Without a remote to ask, the only branch there is will do.
+    let mut branches = repo.branches(Some(git2::BranchType::Local))?;
+    match (branches.next(), branches.next()) {
+        (Some(branch), None) => Ok(branch?.0.name()?.ok_or("branch name is not valid UTF-8")?.to_owned()),
+        _ => bail!("could not work out which branch to check out in '{}'",
+                   repo.workdir().unwrap_or_else(|| repo.path()).display()),
+    }
+}
+
+fn ensure_head_is_named_reference(repo: &Repository) -> Result<(), Error> {
     let head = repo.head()?;
 
-    if head.is_branch() {
-        Ok(())
+    let checked_out = if head.is_branch() {
+        return Ok(());
     } else if head.is_note() {
-        panic!("HEAD is a note!");
+        "a note"
     } else if head.is_remote() {
-        panic!("HEAD is a remote!");
+        "a remote branch"
     } else if head.is_tag() {
-        panic!("HEAD is a tag!");
+        "a tag"
     } else {
-        panic!("an arbitrary commit is checked out");
-    }
+        "an arbitrary commit"
+    };
+
+    bail!(ErrorKind::UnsupportedHead(checked_out.to_owned()))
 }
 
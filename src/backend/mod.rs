@@ -1,12 +1,69 @@
 pub mod git;
 
 use {Source, Error};
-use std::path::Path;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// The state of a dotfiles repository.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepositoryStatus {
+    /// The currently checked out revision, if there is one.
+    pub revision: Option<String>,
+    /// The name of the current branch, if there is one.
+    pub branch: Option<String>,
+    /// Files with uncommitted changes, relative to the repository root.
+    pub dirty: Vec<PathBuf>,
+    /// How many revisions the repository is ahead of and behind its upstream, if it has one.
+    pub ahead_behind: Option<(usize, usize)>,
+}
 
 /// A dotfiles backend.
 pub trait Backend {
     /// Updates the dotfiles.
     fn update(&mut self, verbose: bool) -> Result<(), Error>;
+
+    /// Checks out a branch, tag or commit.
+    ///
+    /// The branch that was checked out when the dotfiles were grabbed is
+    /// checked out again if no reference is given.
+    fn checkout(&mut self, reference: Option<&str>) -> Result<(), Error>;
+
+    /// Gets an identifier for the currently checked out revision, if there is one.
+    fn revision(&self) -> Result<Option<String>, Error>;
+
+    /// Gets the state of the repository.
+    fn status(&self) -> Result<RepositoryStatus, Error>;
+
+    /// Looks for anything that would stop the dotfiles from being updated.
+    ///
+    /// Returns a description of each problem.
+    fn diagnose(&self) -> Result<Vec<String>, Error>;
+
+    /// Reclaims space used by the repository's history.
+    fn compact(&self, verbose: bool) -> Result<(), Error>;
+
+    /// Lists the files the backend manages, relative to the repository root.
+    ///
+    /// Returns `None` if the backend does not know, in which case every file
+    /// in the working directory is treated as managed.
+    fn managed_files(&self) -> Result<Option<BTreeSet<PathBuf>>, Error>;
+
+    /// Lists the nested repositories inside the repository, relative to its root.
+    ///
+    /// Nothing inside them is a dotfile.
+    fn submodules(&self) -> Result<Vec<PathBuf>, Error>;
+
+    /// Starts managing a new file, relative to the repository root.
+    ///
+    /// The file is treated as a dotfile from then on, even before it is committed.
+    fn stage(&self, path: &Path) -> Result<(), Error>;
+}
+
+impl RepositoryStatus {
+    /// Checks if the repository has no uncommitted changes and is in sync with its upstream.
+    pub fn is_clean(&self) -> bool {
+        self.dirty.is_empty() && self.ahead_behind.map(|(ahead, behind)| ahead == 0 && behind == 0).unwrap_or(true)
+    }
 }
 
 /// Initializes a new backend.
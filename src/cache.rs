@@ -1,28 +1,36 @@
-use {SourceSpec, Dotfile, FeatureSet, Error, ResultExt};
-use backend::{self, Backend};
-use {symlink, util};
+use {SourceSpec, Dotfile, DotfileKind, Environment, FeatureSet, Error, ErrorKind, ResultExt};
+use backend::{self, Backend, RepositoryStatus};
+use {diff, generated, hook, lock, permissions, polkignore, repository, secret, symlink, template, util};
+use status::{self, DotfileStatus, State};
+use ledger::{self, Ledger};
+use manifest::UserManifest;
+use journal::{self, Journal};
 
 use walkdir::WalkDir;
-use toml;
 
-use std::io::prelude::*;
-use std::path::{self, Path, PathBuf};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
 use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix;
 use sym;
 
 /// Files which should not be considered dotfiles.
-pub const DOTFILE_FILE_BLACKLIST: &'static [&'static str] = &[
+pub const DOTFILE_FILE_BLACKLIST: &[&str] = &[
     ".gitignore",
     ".git", // Git worktrees have `.git` files.
     ".gitmodules",
-    "README", "README.txt", "README.md", "README.rst", "README.doc",
-    "LICENSE", "LICENSE.txt", "LICENSE.md", "LICENSE.rst", "LICENSE.doc",
-    "HACKING", "HACKING.txt", "HACKING.md", "HACKING.rst", "HACKING.doc",
-    "CONTRIBUTING", "CONTRIBUTING.txt", "CONTRIBUTING.md", "CONTRIBUTING.rst", "CONTRIBUTING.doc",
+    LINK_DIRECTORY_MARKER,
+    repository::CONFIG_FILE_NAME,
+    polkignore::IGNORE_FILE_NAME,
 ];
 
+/// A file which, when placed inside a directory, causes the entire directory
+/// to be symlinked rather than each of the files inside it.
+pub const LINK_DIRECTORY_MARKER: &str = ".polk-link-dir";
+
 /// Folders which we should not recurse into whilst searching for dotfiles.
-pub const DIRECTORY_BLACKLIST: &'static [&'static str] = &[
+pub const DIRECTORY_BLACKLIST: &[&str] = &[
     ".git",
 ];
 
@@ -30,6 +38,8 @@ pub const DIRECTORY_BLACKLIST: &'static [&'static str] = &[
 pub struct Cache {
     /// The directory that contains the cache.
     pub path: PathBuf,
+    /// The environment the cache belongs to.
+    pub environment: Environment,
 }
 
 /// Cache for a particular user.
@@ -38,41 +48,63 @@ pub struct UserCache<'a> {
     pub username: String,
 }
 
-/// A manifest file for a user cache.
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub struct UserManifest {
-    /// The source of the dotfiles.
-    pub source: SourceSpec,
+/// Everything needed to deploy dotfiles.
+struct Generators {
+    /// The values available to templates.
+    context: template::Context,
+    /// The identity for decrypting secrets, if the user has one.
+    identity: Option<secret::Identity>,
+    /// The modes declared by the repository.
+    modes: permissions::Modes,
+    /// The ways the repository declares paths should be deployed.
+    link_modes: repository::LinkModes,
+    /// The directory holding the dotfiles inside the repository.
+    root_path: PathBuf,
+    /// The revision of the dotfiles being deployed.
+    revision: Option<String>,
 }
 
 impl Cache {
-    /// Opens or creates a cache directory given a path.
-    pub fn at(path: PathBuf) -> Result<Self, Error> {
-        if path.exists() { Cache::open(path) } else { Cache::create(path) }
+    /// Opens or creates the cache directory of an environment.
+    pub fn at(environment: Environment) -> Result<Self, Error> {
+        if environment.cache_path.exists() { Cache::open(environment) } else { Cache::create(environment) }
     }
 
-    /// Opens an existing cache directory.
-    pub fn open(path: PathBuf) -> Result<Self, Error> {
-        assert!(path.exists(), "cache must exist before opening");
-        assert!(path.is_dir(), "cache path must be a directory");
+    /// Opens the existing cache directory of an environment.
+    pub fn open(environment: Environment) -> Result<Self, Error> {
+        let path = environment.cache_path.clone();
+
+        if !path.exists() {
+            bail!("there is no cache at '{}'", path.display());
+        }
+
+        if !path.is_dir() {
+            bail!("the cache at '{}' is not a directory", path.display());
+        }
 
-        Ok(Cache { path: path })
+        Ok(Cache { path, environment })
     }
 
-    /// Creates a new cache directory.
-    pub fn create(path: PathBuf) -> Result<Self, Error> {
-        assert!(!path.exists(), "cache already exists in this directory");
+    /// Creates a new cache directory for an environment.
+    pub fn create(environment: Environment) -> Result<Self, Error> {
+        let path = environment.cache_path.clone();
+
+        if path.exists() {
+            bail!("a cache already exists at '{}'", path.display());
+        }
 
         fs::create_dir_all(&path)?;
-        Ok(Cache { path: path })
+        Ok(Cache { path, environment })
     }
 
     /// Clears all symlinks and deletes the cache.
-    pub fn forget(self, _verbose: bool) -> Result<(), Error> {
-        for mut user_cache in self.user_caches()? {
-            // Don't be verbose because there will be many false positives.
-            user_cache.unlink(true).chain_err(|| "could not forget symlinks")?;
-        }
+    pub fn forget(self, verbose: bool) -> Result<(), Error> {
+        let mut ledger = self.ledger()?;
+
+        journal::run(verbose, |journal| {
+            ledger.remove_links(journal, verbose, |_| true)?;
+            ledger.remove_empty_directories(journal, verbose)
+        }).chain_err(|| "could not forget symlinks")?;
 
         if self.path.exists() {
             fs::remove_dir_all(&self.path).chain_err(|| "could not remote cache")?;
@@ -81,20 +113,62 @@ impl Cache {
         Ok(())
     }
 
+    /// Moves the cache to another directory, updating every link that points into it.
+    pub fn migrate(self, path: &Path, verbose: bool) -> Result<Cache, Error> {
+        if fs::symlink_metadata(path).is_ok() {
+            bail!("cannot move the cache to '{}' as it already exists", path.display());
+        }
+
+        let mut ledger = self.ledger()?;
+
+        journal::run(verbose, |journal| {
+            for entry in ledger.links.iter_mut() {
+                let target = match entry.target.strip_prefix(&self.path) {
+                    Ok(relative_target) => path.join(relative_target),
+                    Err(..) => continue,
+                };
+
+                if entry.mode == ledger::Mode::Symlink && entry.is_intact() {
+                    vlog!(verbose => "relinking {} -> {}", entry.path.display(), target.display());
+                    journal.track(&entry.path)?;
+                    fs::remove_file(&entry.path)?;
+                    unix::fs::symlink(&target, &entry.path)?;
+                }
+
+                entry.target = target;
+            }
+
+            // Changes inside a moved directory cannot be rolled back, so the
+            // ledger has to be saved before the cache is moved.
+            self.save_ledger(&ledger, journal)?;
+
+            journal.track(path)?;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            journal.rename(&self.path, path)
+        }).chain_err(|| format!("could not move the cache to '{}'", path.display()))?;
+
+        let environment = Environment { cache_path: path.to_owned(), ..self.environment };
+        Ok(Cache { path: path.to_owned(), environment })
+    }
+
     /// Gets all of the dotfile caches.
-    pub fn user_caches(&self) -> Result<Vec<UserCache>, Error> {
+    pub fn user_caches(&self) -> Result<Vec<UserCache<'_>>, Error> {
         let mut usernames = Vec::new();
 
         if !self.users_path().exists() {
             return Ok(Vec::new());
         }
 
-        for entry in fs::read_dir(&self.users_path())? {
+        for entry in fs::read_dir(self.users_path())? {
             let path = entry?.path();
 
             if path.is_dir() {
-                let file_name = path.file_name().unwrap().to_str().unwrap();
-                usernames.push(file_name.to_owned());
+                // Usernames are always valid UTF-8, so anything else was not created by polk.
+                if let Some(file_name) = path.file_name().and_then(|n| n.to_str()) {
+                    usernames.push(file_name.to_owned());
+                }
             }
         }
 
@@ -102,18 +176,114 @@ impl Cache {
     }
 
     /// Gets a user-specifc cache.
-    pub fn user<S>(&self, username: S) -> UserCache where S: Into<String> {
+    pub fn user<S>(&self, username: S) -> UserCache<'_> where S: Into<String> {
         UserCache { cache: self, username: username.into() }
     }
 
     /// Gets the `users` directory path.
     pub fn users_path(&self) -> PathBuf { self.path.join("users") }
+
+    /// Gets the path to the ledger of created links.
+    pub fn ledger_path(&self) -> PathBuf { self.path.join("ledger.toml") }
+
+    /// Loads the ledger of created links.
+    ///
+    /// Caches from before the ledger existed have their links adopted into
+    /// a new ledger, so that they can still be removed.
+    pub fn ledger(&self) -> Result<Ledger, Error> {
+        if self.ledger_path().exists() {
+            Ledger::load(&self.ledger_path())
+        } else {
+            self.adopt_links()
+        }
+    }
+
+    /// Builds a ledger out of the symlinks that already point into the cache.
+    ///
+    /// Older versions of polk only ever created symlinks in the home directory,
+    /// so only the places dotfiles would be linked to are looked at.
+    fn adopt_links(&self) -> Result<Ledger, Error> {
+        let mut ledger = Ledger::default();
+        let symlink_config = symlink::Config::new(&self.environment);
+
+        for user_cache in self.user_caches()? {
+            let dotfiles = match user_cache.dotfiles() {
+                Ok(dotfiles) => dotfiles,
+                Err(e) => {
+                    warn!("could not look for links to the dotfiles of {}: {}", user_cache.username, e);
+                    continue;
+                },
+            };
+
+            let mut paths: Vec<_> = dotfiles.iter().map(|dotfile| symlink::path(dotfile, &symlink_config)).collect();
+            paths.push(user_cache.shortcut_symlink_path(&symlink_config));
+
+            for path in paths {
+                if let Ok(target) = fs::read_link(&path) {
+                    if target.starts_with(user_cache.base_path()) {
+                        ledger.record(user_cache.ledger_entry(path, target, &symlink_config, &None));
+                    }
+                }
+            }
+        }
+
+        Ok(ledger)
+    }
+
+    /// Saves the ledger of created links.
+    pub fn save_ledger(&self, ledger: &Ledger, journal: &mut Journal) -> Result<(), Error> {
+        journal.track(&self.ledger_path())?;
+        ledger.save(&self.ledger_path()).chain_err(|| "could not save link ledger")
+    }
+
+    /// Changes the ledger of created links along with the filesystem, rolling
+    /// both back if anything fails.
+    ///
+    /// The ledger is locked from when it is loaded until it is saved, so that
+    /// processes working on different users never lose each other's links.
+    /// See `lock::acquire` for `wait`.
+    pub fn change_ledger<T, F>(&self, wait: bool, verbose: bool, f: F) -> Result<T, Error>
+        where F: FnOnce(&mut Ledger, &mut Journal) -> Result<T, Error> {
+        let _lock = lock::acquire(&self.path.join("ledger.lock"), lock::Kind::Exclusive, wait)?;
+        let mut ledger = self.ledger()?;
+
+        journal::run(verbose, |journal| {
+            let value = f(&mut ledger, journal)?;
+            self.save_ledger(&ledger, journal)?;
+            Ok(value)
+        })
+    }
+
+    /// Locks the whole cache against other polk processes.
+    ///
+    /// Anything that changes more than one user's dotfiles, or the cache
+    /// itself, should hold this lock. See `lock::acquire` for `wait`.
+    pub fn lock(&self, wait: bool) -> Result<lock::Lock, Error> {
+        lock::acquire(&self.lock_path(), lock::Kind::Exclusive, wait)
+    }
+
+    /// Gets the path to the lock file for the whole cache.
+    fn lock_path(&self) -> PathBuf { self.path.join("polk.lock") }
 }
 
 impl<'a> UserCache<'a> {
+    /// The environment the cache belongs to.
+    pub fn environment(&self) -> &Environment { &self.cache.environment }
+
     /// The path to the root of the user cache.
     pub fn base_path(&self) -> PathBuf { self.cache.users_path().join(&self.username) }
 
+    /// Locks the user cache against other polk processes.
+    ///
+    /// Other users' caches can still be used at the same time, but the whole
+    /// cache cannot be locked. See `lock::acquire` for `wait`.
+    pub fn lock(&self, wait: bool) -> Result<lock::Lock, Error> {
+        let cache_lock = lock::acquire(&self.cache.lock_path(), lock::Kind::Shared, wait)?;
+        let lock_path = self.cache.path.join("locks").join(format!("{}.lock", self.username));
+
+        Ok(cache_lock.join(lock::acquire(&lock_path, lock::Kind::Exclusive, wait)?))
+    }
+
     /// Gets the path to the manifest file.
     pub fn manifest_path(&self) -> PathBuf {
         self.base_path().join("manifest.toml")
@@ -124,20 +294,44 @@ impl<'a> UserCache<'a> {
         self.base_path().join("dotfiles")
     }
 
+    /// The path to the user's local template variables.
+    ///
+    /// This lives outside of the dotfiles repository so that it is never committed.
+    pub fn vars_path(&self) -> PathBuf {
+        self.base_path().join("vars.toml")
+    }
+
+    /// The path to the user's identity for decrypting secrets.
+    ///
+    /// This lives outside of the dotfiles repository so that it is never committed.
+    pub fn identity_path(&self) -> PathBuf {
+        self.base_path().join("identity.txt")
+    }
+
     /// The home directory for custom shells.
     pub fn home_path(&self) -> PathBuf {
         self.base_path().join("home")
     }
 
-    pub fn shortcut_symlink_path(&self) -> PathBuf {
-        util::home_dir().join(".dot")
+    /// The path of the shortcut symlink to the dotfiles repository.
+    pub fn shortcut_symlink_path(&self, symlink_config: &symlink::Config) -> PathBuf {
+        symlink_config.home_path.join(".dot")
     }
 
     /// Fetches dotfiles *and* creates symlinks.
-    pub fn setup(&mut self, source: &SourceSpec, verbose: bool) -> Result<(), Error> {
+    pub fn setup(&mut self, source: &SourceSpec, wait: bool, verbose: bool) -> Result<(), Error> {
+        self.setup_ext(source, &symlink::Config::new(self.environment()), wait, verbose)
+    }
+
+    /// Fetches dotfiles *and* creates symlinks, with more options.
+    pub fn setup_ext(&mut self,
+                     source: &SourceSpec,
+                     symlink_config: &symlink::Config,
+                     wait: bool,
+                     verbose: bool) -> Result<(), Error> {
         self.grab(source, verbose).chain_err(|| "failed to grab dotfiles")?;
 
-        self.link_ext(&symlink::Config::default(), verbose).
+        self.link_ext(symlink_config, wait, verbose).
             chain_err(|| "could not build symlinks")
     }
 
@@ -147,18 +341,21 @@ impl<'a> UserCache<'a> {
         if let Some(parent) = self.dotfiles_path().parent() {
             if !parent.exists() {
                 vlog!(verbose => "{} does not exist, creating it", parent.display());
-                fs::create_dir_all(&parent)?;
+                fs::create_dir_all(parent)?;
             }
         }
 
-        backup::path(self.dotfiles_path(), || {
+        journal::run(verbose, |journal| {
+            journal.move_aside(&self.dotfiles_path())?;
+
             // Create the manifest file and save it to disk.
-            let manifest = UserManifest { source: source.clone() };
+            let manifest = UserManifest::new(source.clone());
+            journal.track(&self.manifest_path())?;
             manifest.save(&self.manifest_path()).chain_err(|| "could not save user cache manifest")?;
 
             // Set up the Git repository, etc
             backend::setup(&self.dotfiles_path(), manifest.source)?;
-            Ok(())
+            self.apply_modes(journal, verbose)
         })
     }
 
@@ -169,400 +366,1144 @@ impl<'a> UserCache<'a> {
     }
 
     /// Updates all of the dotfiles.
-    pub fn update(&mut self, verbose: bool) -> Result<(), Error> {
+    pub fn update(&mut self, wait: bool, verbose: bool) -> Result<(), Error> {
         if !self.is_grabbed() {
-            fatal!("cannot update, there are no dotfiles grabbed for this user");
+            bail!(ErrorKind::NotGrabbed(self.username.clone()));
         }
 
-        let (manifest, mut backend) = self.open_manifest_backend()?;
+        let (mut manifest, mut backend) = self.open_manifest_backend()?;
+
+        if let Some(ref pinned_ref) = manifest.pinned_ref {
+            ilog!("not updating the dotfiles for user '{}' as they are pinned to '{}', run `polk update --unpin` to update them",
+                  self.username, pinned_ref);
+            return Ok(());
+        }
 
         ilog!("updating dotfiles from {}", manifest.source.description());
-        backend.update(verbose)
+        backend.update(verbose)?;
+        journal::run(verbose, |journal| self.apply_modes(journal, verbose))?;
+
+        manifest.touch();
+        manifest.save(&self.manifest_path()).chain_err(|| "could not save user cache manifest")?;
+
+        self.regenerate(wait, verbose).chain_err(|| "could not regenerate templates and secrets")?;
+
+        if let Some(ref command) = self.repository_config()?.hooks.post_update {
+            self.run_hook("post_update", command, &self.environment().home_path, verbose)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks out a branch, tag or commit and stops `update` from changing it.
+    pub fn pin(&mut self, reference: &str, verbose: bool) -> Result<(), Error> {
+        self.checkout(Some(reference), verbose)
+    }
+
+    /// Checks out the branch the dotfiles were grabbed from again, so that `update` changes them.
+    pub fn unpin(&mut self, verbose: bool) -> Result<(), Error> {
+        self.checkout(None, verbose)
+    }
+
+    /// Checks out a revision and records it as the one the dotfiles are pinned to.
+    fn checkout(&mut self, reference: Option<&str>, verbose: bool) -> Result<(), Error> {
+        if !self.is_grabbed() {
+            bail!(ErrorKind::NotGrabbed(self.username.clone()));
+        }
+
+        let (mut manifest, mut backend) = self.open_manifest_backend()?;
+
+        if reference.is_none() && manifest.pinned_ref.is_none() {
+            return Ok(());
+        }
+
+        backend.checkout(reference)?;
+        journal::run(verbose, |journal| self.apply_modes(journal, verbose))?;
+
+        manifest.pinned_ref = reference.map(ToOwned::to_owned);
+        manifest.save(&self.manifest_path()).chain_err(|| "could not save user cache manifest")
     }
 
     /// Creates all symlinks.
-    pub fn link(&mut self, verbose: bool) -> Result<(), Error> {
-        self.link_ext(&symlink::Config::default(), verbose)
+    pub fn link(&mut self, wait: bool, verbose: bool) -> Result<(), Error> {
+        self.link_ext(&symlink::Config::new(self.environment()), wait, verbose)
     }
 
     /// Creates all symlinks, with more options.
     pub fn link_ext(&mut self,
                     symlink_config: &symlink::Config,
+                    wait: bool,
                     verbose: bool) -> Result<(), Error> {
-        let features = FeatureSet::current_system();
+        self.deploy(symlink_config, false, wait, verbose)
+    }
 
-        for mut dotfile in self.dotfiles()? {
-            if features.supports(&dotfile) {
-                features.substitute_enabled_feature_names(&mut dotfile);
-                symlink::build(&dotfile, &symlink_config)?;
+    /// Deletes and recreates all symlinks.
+    ///
+    /// If anything fails, the old symlinks are put back.
+    pub fn relink(&mut self,
+                  symlink_config: &symlink::Config,
+                  wait: bool,
+                  verbose: bool) -> Result<(), Error> {
+        self.deploy(symlink_config, true, wait, verbose)
+    }
 
-                let symlink_path = symlink::path(&dotfile, &symlink_config);
-                vlog!(verbose => "created {} -> {}", dotfile.full_path.display(), symlink_path.display());
-            } else {
-                ilog!("ignoring '{}' because is is not supported by this machine",
-                      dotfile.relative_path.display());
-            }
+    /// Creates all symlinks, optionally deleting the existing ones first.
+    fn deploy(&mut self,
+              symlink_config: &symlink::Config,
+              unlink_first: bool,
+              wait: bool,
+              verbose: bool) -> Result<(), Error> {
+        let hooks = self.repository_config()?.hooks;
+
+        if let Some(ref command) = hooks.pre_link {
+            self.run_hook("pre_link", command, &symlink_config.home_path, verbose)?;
         }
 
-        if self.shortcut_symlink_path().exists() {
-            if self.shortcut_symlink_path().symlink_metadata().is_ok() {
-                // Remove the existing symlink.
-                sym::remove_symlink_dir(&self.shortcut_symlink_path()).chain_err(|| "could not create remove existing to dotfiles repository")?;
+        // Either everything is linked, or nothing is.
+        self.cache.change_ledger(wait, verbose, |ledger, journal| {
+            if unlink_first {
+                self.unlink_from_ledger(ledger, symlink_config, journal, verbose)?;
             }
+
+            self.link_into_ledger(ledger, symlink_config, journal, verbose)?;
+            self.record_link(symlink_config, journal)
+        })?;
+
+        if let Some(ref command) = hooks.post_link {
+            self.run_hook("post_link", command, &symlink_config.home_path, verbose)?;
         }
-        sym::symlink_dir(self.dotfiles_path(), self.shortcut_symlink_path()).chain_err(|| "could not create symlink to dotfiles repository")?;
 
         Ok(())
     }
 
-    /// Deletes all symbolic links.
-    pub fn unlink(&mut self, verbose: bool) -> Result<(), Error> {
-        let symlink_config = symlink::Config::default();
-
-        for dotfile in self.dotfiles()? {
-            if symlink::exists(&dotfile, &symlink_config)? {
-                vlog!(verbose => "deleting {}",
-                      symlink::path(&dotfile, &symlink_config).display());
-
-                symlink::destroy(&dotfile, &symlink_config)?;
-            }
+    /// Runs one of the repository's hooks, if hooks are enabled for this user.
+    fn run_hook(&self, name: &str, command: &str, home_path: &Path, verbose: bool) -> Result<(), Error> {
+        if self.username != self.environment().real_username {
+            ilog!("not running the {} hook as the dotfiles belong to {}", name, self.username);
+            return Ok(());
         }
 
-        if self.shortcut_symlink_path().symlink_metadata().is_ok() {
-            sym::remove_symlink_dir(&self.shortcut_symlink_path()).chain_err(|| "could not create remove existing to dotfiles repository")?;
+        if !self.environment().run_hooks {
+            ilog!("not running the {} hook as hooks are not enabled", name);
+            return Ok(());
         }
 
-        Ok(())
+        hook::run(name, command, &self.root_path()?, home_path, verbose)
     }
 
-    /// Gets all of the dotfiles in the cache.
-    pub fn dotfiles(&self) -> Result<Vec<Dotfile>, Error> {
-        let mut dotfiles = Vec::new();
-
-        if !self.dotfiles_path().exists() {
-            return Ok(Vec::new());
+    /// Records in the manifest how the dotfiles were linked.
+    fn record_link(&self, symlink_config: &symlink::Config, journal: &mut Journal) -> Result<(), Error> {
+        if !self.is_grabbed() {
+            return Ok(());
         }
 
-        for entry in WalkDir::new(self.dotfiles_path()) {
-            let entry = entry?;
+        let mut manifest = self.manifest()?;
+        let mut features: Vec<_> = FeatureSet::for_environment(self.environment())?.enabled_features.iter().
+            map(|feature| feature.to_string()).
+            collect();
+        features.sort();
 
-            if !entry.path().is_file() { continue; }
+        manifest.last_linked_revision = self.revision()?;
+        manifest.linked_features = features;
+        manifest.link_mode = Some(symlink_config.mode);
+        manifest.target = Some(symlink_config.home_path.clone());
 
-            let file_name = entry.path().file_name().unwrap().to_str().unwrap().to_owned();
+        journal.track(&self.manifest_path())?;
+        manifest.save(&self.manifest_path()).chain_err(|| "could not save user cache manifest")
+    }
 
-            // Check that none of the parent folders are blacklisted.
-            let folder_blacklisted = entry.path().components().any(|comp| {
-                if let path::Component::Normal(ref p) = comp {
-                    DIRECTORY_BLACKLIST.iter().any(|bl| bl == p)
-                } else {
-                    false
-                }
-            });
-            let file_blacklisted = DOTFILE_FILE_BLACKLIST.iter().any(|&bl| file_name == bl);
-            let mut is_submodule = false;
-            let mut current_path = Some(entry.path().to_owned());
+    /// Creates all symlinks, recording each one in the ledger.
+    fn link_into_ledger(&self,
+                        ledger: &mut Ledger,
+                        symlink_config: &symlink::Config,
+                        journal: &mut Journal,
+                        verbose: bool) -> Result<(), Error> {
+        let features = FeatureSet::for_environment(self.environment())?;
+        let generators = self.generators(&features)?;
+        // Looking for dotfiles walks the whole repository, so it is only done once.
+        let dotfiles = self.dotfiles()?;
+
+        self.apply_declared_modes(&dotfiles, journal, verbose)?;
+
+        for mut dotfile in dotfiles {
+            // The ledger can only record paths that are valid UTF-8.
+            if dotfile.full_path.to_str().is_none() || dotfile.relative_path.to_str().is_none() {
+                warn!("not linking '{}' as its name is not valid UTF-8", dotfile.full_path.display());
+                continue;
+            }
 
-            while let Some(path) = current_path {
-                // Stop looking once we hit the top level.
-                if self.dotfiles_path() == path { break; }
+            if features.supports(&dotfile) {
+                features.substitute_enabled_feature_names(&mut dotfile)?;
 
-                if path.join(".git").exists() {
-                    is_submodule = true;
-                    break;
-                }
+                let is_copied = self::is_copied(&dotfile, symlink_config, &generators);
 
-                current_path = path.parent().map(ToOwned::to_owned);
+                if dotfile.kind == DotfileKind::Template || dotfile.kind == DotfileKind::Secret || is_copied {
+                    self.generate_into_ledger(ledger, &dotfile, symlink_config, &generators, journal, verbose)?;
+                } else {
+                    let link = match self.resolve_conflicts(ledger, symlink_config, journal, verbose, |journal| {
+                        symlink::build(&dotfile, symlink_config, journal)
+                    })? {
+                        Some(link) => link,
+                        None => continue,
+                    };
+                    vlog!(verbose => "created {} -> {}", dotfile.full_path.display(), link.path.display());
+
+                    if let Some(mode) = generators.modes.directory_mode(self::repository_path(&dotfile.full_path, &generators.root_path)) {
+                        self.apply_directory_mode(&link.created_directories, mode)?;
+                    }
+
+                    let entry = self.ledger_entry(link.path, dotfile.full_path.clone(), symlink_config, &generators.revision);
+                    self.record(ledger, entry, link.created_directories);
+                }
+            } else {
+                ilog!("ignoring '{}' because it is not supported by this machine",
+                      dotfile.relative_path.display());
             }
+        }
 
-            if !folder_blacklisted && !file_blacklisted && !is_submodule {
-                dotfiles.push(Dotfile {
-                    full_path: entry.path().to_owned(),
-                    relative_path: entry.path().strip_prefix(&self.dotfiles_path()).unwrap().to_owned(),
-                });
+        let shortcut_path = self.shortcut_symlink_path(symlink_config);
+        journal.track(&shortcut_path)?;
+
+        if let Ok(metadata) = shortcut_path.symlink_metadata() {
+            if !metadata.file_type().is_symlink() {
+                warn!("there is an existing file at '{}', will not create symlink to dotfiles repository",
+                      shortcut_path.display());
+                return Ok(());
             }
+
+            // Remove the existing symlink.
+            sym::remove_symlink_dir(&shortcut_path).chain_err(|| "could not create remove existing to dotfiles repository")?;
         }
+        sym::symlink_dir(self.dotfiles_path(), &shortcut_path).chain_err(|| "could not create symlink to dotfiles repository")?;
+        let entry = self.ledger_entry(shortcut_path, self.dotfiles_path(), symlink_config, &generators.revision);
+        self.record(ledger, entry, Vec::new());
 
-        Ok(dotfiles)
+        Ok(())
     }
 
-    /// Gets the manifest.
-    pub fn manifest(&self) -> Result<UserManifest, Error> {
-        UserManifest::load(&self.manifest_path()).chain_err(|| "reading user manifest")
+    /// Deletes all symbolic links that were created for this user.
+    pub fn unlink(&mut self, wait: bool, verbose: bool) -> Result<(), Error> {
+        self.unlink_ext(&symlink::Config::new(self.environment()), wait, verbose)
     }
 
-    /// Gets the manifest and backend.
-    fn open_manifest_backend(&self) -> Result<(UserManifest, Box<dyn Backend>), Error> {
-        let manifest = self.manifest()?;
-        backend::open(&self.dotfiles_path(), manifest.source.clone()).map(|b| (manifest, b))
+    /// Deletes all symbolic links that were created for this user, with more options.
+    ///
+    /// Only links created inside the configured home directory are removed.
+    pub fn unlink_ext(&mut self,
+                      symlink_config: &symlink::Config,
+                      wait: bool,
+                      verbose: bool) -> Result<(), Error> {
+        self.cache.change_ledger(wait, verbose, |ledger, journal| {
+            self.unlink_from_ledger(ledger, symlink_config, journal, verbose)
+        })
     }
-}
 
-impl UserManifest {
-    /// Loads the manifest from disk.
-    pub fn load(path: &Path) -> Result<Self, Error> {
-        let mut file = fs::File::open(path)?;
-        let mut manifest_toml = String::new();
-        file.read_to_string(&mut manifest_toml)?;
-
-        Ok(toml::from_str(&manifest_toml).expect("could not parse user manifest"))
+    /// Deletes all symbolic links that were created for this user, removing them from the ledger.
+    fn unlink_from_ledger(&self,
+                          ledger: &mut Ledger,
+                          symlink_config: &symlink::Config,
+                          journal: &mut Journal,
+                          verbose: bool) -> Result<(), Error> {
+        ledger.remove_links(journal, verbose, |entry| {
+            entry.user == self.username && entry.home == symlink_config.home_path
+        })?;
+        ledger.remove_empty_directories(journal, verbose)
     }
 
-    /// Saves the manifest to disk.
-    pub fn save(&self, path: &Path) -> Result<(), Error> {
-        let manifest_toml = toml::to_string(self).expect("failed to create manifest toml");
-
-        let mut file = fs::File::create(path)?;
-        file.write_all(manifest_toml.as_bytes())?;
-        Ok(())
+    /// Regenerates every template, secret and copy that has previously been written for this user.
+    ///
+    /// Generated files which have been modified since they were written are left alone.
+    pub fn regenerate(&mut self, wait: bool, verbose: bool) -> Result<(), Error> {
+        self.cache.change_ledger(wait, verbose, |ledger, journal| {
+            self.regenerate_into_ledger(ledger, journal, verbose)
+        })
     }
-}
 
-mod backup {
-    use {Error, ResultExt};
-    use rand::random;
-    use std::path::Path;
-    use std::fs;
+    fn regenerate_into_ledger(&self, ledger: &mut Ledger, journal: &mut Journal, verbose: bool) -> Result<(), Error> {
+        let features = FeatureSet::for_environment(self.environment())?;
+        let generators = self.generators(&features)?;
 
-    /// Move a file to a temporary location and restore it
-    /// in the event of an error.
-    pub fn path<P,T,F>(path: P, f: F) -> Result<T, Error>
-        where P: AsRef<Path>, F: FnOnce() -> Result<T, Error> {
-        let path = path.as_ref();
+        let generated: Vec<_> = ledger.links.iter().filter(|entry| {
+            entry.user == self.username && entry.mode != ledger::Mode::Symlink
+        }).cloned().collect();
 
-        if !path.exists() {
-            // Nothing to back up in that case.
-            return f();
+        for entry in generated {
+            if !entry.target.exists() {
+                vlog!(verbose => "{} no longer exists", entry.target.display());
+                ledger.remove_links(journal, verbose, |e| e.path == entry.path)?;
+                continue;
+            }
+
+            let symlink_config = symlink::Config::with_home(entry.home.clone());
+            let dotfile = Dotfile {
+                full_path: entry.target.clone(),
+                relative_path: entry.path.strip_prefix(&entry.home).map(ToOwned::to_owned).unwrap_or_else(|_| entry.path.clone()),
+                kind: match entry.mode {
+                    ledger::Mode::Decrypted => DotfileKind::Secret,
+                    ledger::Mode::Copied => DotfileKind::File,
+                    _ => DotfileKind::Template,
+                },
+            };
+
+            self.generate_into_ledger(ledger, &dotfile, &symlink_config, &generators, journal, verbose)?;
         }
 
-        let file_name = path.file_name().expect("cannot have transactions on paths with no file name").to_str().unwrap();
-        let temp_path = path.parent().unwrap_or(path).join(format!(".{}.{}.backup", file_name, random_token()));
+        ledger.remove_empty_directories(journal, verbose)
+    }
+
+    /// Renders a template, decrypts a secret or copies a file, recording the generated file in the ledger.
+    fn generate_into_ledger(&self,
+                            ledger: &mut Ledger,
+                            dotfile: &Dotfile,
+                            symlink_config: &symlink::Config,
+                            generators: &Generators,
+                            journal: &mut Journal,
+                            verbose: bool) -> Result<(), Error> {
+        let mode = match dotfile.kind {
+            DotfileKind::Template => ledger::Mode::Rendered,
+            DotfileKind::Secret => ledger::Mode::Decrypted,
+            DotfileKind::File => ledger::Mode::Copied,
+            DotfileKind::Directory => unreachable!("directories are always symlinked"),
+        };
+
+        let previous_checksum = ledger.entry(&symlink::path(dotfile, symlink_config)).
+            filter(|e| e.mode == mode).
+            and_then(|e| e.checksum.clone());
+        let previous_checksum = previous_checksum.as_deref();
 
-        // We don't need special error handling here because if we fail to backup,
-        // we won't even end up executing the function.
-        backup_path(path, &temp_path)?;
+        let repository_path = self::repository_path(&dotfile.full_path, &generators.root_path);
+        let file_mode = generators.modes.file_mode(repository_path);
 
-        match f() {
-            Ok(result) => Ok(result),
-            // An error occurred, attempt to restore the path.
-            Err(e) => match restore_path(path, &temp_path) {
-                // Successfully restored the file, propagate error.
-                Ok(..) => Err(e),
-                // Failure during restoration, add more context and propagate up.
-                Err(f) => Err(f).chain_err(|| format!("could not restore backed up file '{}'", path.display())),
+        let generated = self.resolve_conflicts(ledger, symlink_config, journal, verbose, |journal| match mode {
+            ledger::Mode::Rendered => {
+                template::deploy(dotfile, symlink_config, &generators.context, file_mode, previous_checksum, journal)
             },
+            ledger::Mode::Decrypted => {
+                let identity = match generators.identity {
+                    Some(ref identity) => identity,
+                    None => bail!("cannot decrypt '{}' without an identity at '{}'",
+                                  dotfile.full_path.display(), self.identity_path().display()),
+                };
+
+                secret::deploy(dotfile, symlink_config, identity, &self.dotfiles_path(), file_mode, previous_checksum, journal)
+            },
+            _ => generated::copy(dotfile, symlink_config, file_mode, previous_checksum, journal),
+        })?;
+
+        let generated = match generated {
+            Some(generated) => generated,
+            None => return Ok(()),
+        };
+        vlog!(verbose => "generated {} from {}", generated.path.display(), dotfile.full_path.display());
+
+        if let Some(mode) = generators.modes.directory_mode(repository_path) {
+            self.apply_directory_mode(&generated.created_directories, mode)?;
         }
-    }
 
-    /// Backup a path.
-    fn backup_path(path: &Path, temp_path: &Path) -> Result<(), Error> {
-        ilog!("backing up {} to {}", path.display(), temp_path.display());
-        fs::rename(path, &temp_path).chain_err(|| "failed to move file to temporary path")?;
+        let mut entry = self.ledger_entry(generated.path, dotfile.full_path.clone(), symlink_config, &generators.revision);
+        entry.mode = mode;
+        entry.checksum = Some(generated.checksum);
+        self.record(ledger, entry, generated.created_directories);
+
         Ok(())
     }
 
-    /// Restore a path.
-    fn restore_path(path: &Path, temp_path: &Path) -> Result<(), Error> {
-        ilog!("restoring {} to {}", temp_path.display(), path.display());
+    /// Deploys a dotfile, dealing with anything in the way according to the conflict policy.
+    ///
+    /// Returns `None` if the dotfile was skipped.
+    fn resolve_conflicts<T, F>(&self,
+                               ledger: &Ledger,
+                               symlink_config: &symlink::Config,
+                               journal: &mut Journal,
+                               verbose: bool,
+                               mut deploy: F) -> Result<Option<T>, Error>
+        where F: FnMut(&mut Journal) -> Result<T, Error> {
+        let (path, existing) = match deploy(journal) {
+            Err(Error(ErrorKind::LinkConflict(path, existing), _)) => (path, existing),
+            result => return result.map(Some),
+        };
 
-        // If somebody has put a file in our place, delete it.
-        if path.exists() {
-            if path.is_dir() {
-                fs::remove_dir_all(path).chain_err(|| "could not remove dirty directory")?;
-            } else {
-                fs::remove_file(path).chain_err(|| "could not remove dirty file")?;
-            }
+        // Symlinks polk created are always replaced, whatever they point to now.
+        let is_ours = ledger.entry(&path).map(|entry| entry.mode == ledger::Mode::Symlink && entry.is_intact()).unwrap_or(false);
+
+        if is_ours {
+            vlog!(verbose => "replacing the symlink polk created at '{}'", path.display());
+            journal.move_aside(&path)?;
+            return deploy(journal).map(Some);
         }
 
-        fs::rename(&temp_path, path).chain_err(|| "could not move backup file to original path")?;
-        Ok(())
+        match symlink_config.conflicts {
+            symlink::Conflicts::Skip => {
+                warn!("there is an existing {} at '{}', leaving it alone", existing, path.display());
+                return Ok(None);
+            },
+            symlink::Conflicts::Fail => bail!(ErrorKind::LinkConflict(path, existing)),
+            symlink::Conflicts::Overwrite => {
+                vlog!(verbose => "replacing the existing {} at '{}'", existing, path.display());
+                journal.move_aside(&path)?;
+            },
+            symlink::Conflicts::Backup => {
+                let mut backup_name = match path.file_name() {
+                    Some(file_name) => file_name.to_owned(),
+                    None => bail!("cannot back up '{}' as it has no file name", path.display()),
+                };
+                backup_name.push(".polk-backup");
+                let backup_path = path.with_file_name(backup_name);
+
+                ilog!("moving the existing {} at '{}' to '{}'", existing, path.display(), backup_path.display());
+                journal.rename(&path, &backup_path)?;
+            },
+        }
+
+        deploy(journal).map(Some)
     }
 
-    /// Generates a random token text.
-    fn random_token() -> String {
-        let (a,b): (u32,u32) = (random(), random());
-        format!("{}{}", a, b)
+    /// Gives the files in the dotfiles repository the modes declared in its configuration.
+    ///
+    /// Git only tracks the executable bit, so files are otherwise checked out
+    /// with the default permissions. The old modes are put back if the
+    /// journal is rolled back.
+    pub fn apply_modes(&self, journal: &mut Journal, verbose: bool) -> Result<(), Error> {
+        if !self.is_grabbed() {
+            return Ok(());
+        }
+
+        self.apply_declared_modes(&self.dotfiles()?, journal, verbose)
     }
-}
 
-// FIXME: Get this test suite working.
-#[cfg(sdfasfdf)]
-mod test {
-    use super::*;
-    use SourceSpec;
-    use symlink;
+    /// Gives some dotfiles the modes declared in the repository's configuration.
+    fn apply_declared_modes(&self, dotfiles: &[Dotfile], journal: &mut Journal, verbose: bool) -> Result<(), Error> {
+        let config = self.repository_config()?;
+        let modes = config.modes()?;
 
-    use rand::random;
-    use git2;
+        for (path, mode) in self::declared_modes(dotfiles, &modes, &config.root_path(&self.dotfiles_path())?)? {
+            vlog!(verbose => "setting mode of {} to {:o}", path.display(), mode);
+            journal.set_mode(&path, mode)?;
+        }
 
-    use std::path::PathBuf;
-    use std::env;
+        Ok(())
+    }
 
-    lazy_static! {
-        /// A path which contains a git repository with dotfiles.
-        static ref DOTFILES_REPO_PATH: PathBuf = {
-            let path = self::temp_directory();
+    /// Finds the files that are more accessible than their declared modes allow.
+    ///
+    /// This covers the given dotfiles, as well as files generated from them.
+    pub fn mode_violations(&self,
+                           dotfiles: &[Dotfile],
+                           symlink_config: &symlink::Config) -> Result<Vec<permissions::Violation>, Error> {
+        let config = self.repository_config()?;
+        let modes = config.modes()?;
+        let root_path = config.root_path(&self.dotfiles_path())?;
+        let mut violations = Vec::new();
+
+        for (path, mode) in self::declared_modes(dotfiles, &modes, &root_path)? {
+            violations.extend(permissions::check(&path, mode)?);
+        }
 
-            let mut repo = git2::Repository::init(&path).expect("failed to create repository");
-            self::populate_dotfiles_repository(&mut repo, DOTFILES);
-            path
-        };
+        let ledger = self.cache.ledger()?;
+        let generated = ledger.links.iter().filter(|entry| {
+            entry.user == self.username && entry.home == symlink_config.home_path && entry.mode != ledger::Mode::Symlink
+        });
 
-        static ref DOTFILES_SOURCE: SourceSpec = {
-            let url = format!("file://{}", DOTFILES_REPO_PATH.display());
-            SourceSpec::Url(url)
-        };
+        for entry in generated.filter(|entry| entry.is_intact()) {
+            let repository_path = self::repository_path(&entry.target, &root_path);
+            let mode = match (modes.file_mode(repository_path), entry.mode) {
+                (Some(mode), _) => mode,
+                (None, ledger::Mode::Decrypted) => secret::PERMISSIONS,
+                (None, _) => continue,
+            };
+
+            violations.extend(permissions::check(&entry.path, mode)?);
+        }
+
+        Ok(violations)
     }
 
-    /// Example dotfiles.
-    const DOTFILES: &'static [(&'static str, &'static str)] = &[
-        (".vimrc", "set ruler\nset sw=2\nset ts=2\n\n"),
-        (".bashrc", "export PATH=/foo/bar:$PATH"),
-    ];
+    /// Gives directories that were created to hold a dotfile their declared mode.
+    fn apply_directory_mode(&self, directories: &[PathBuf], mode: u32) -> Result<(), Error> {
+        for directory in directories {
+            permissions::apply(directory, mode)?;
+        }
 
-    /// Get a temporary directory path.
-    fn temp_directory() -> PathBuf {
-        let (a,b): (u32,u32) = (random(), random());
-        env::temp_dir().join(format!("cache-{}-{}", a, b))
+        Ok(())
     }
 
-    /// Runs a function with a freshly-created cache.
-    fn with_cache<F>(f: F)
-        where F: FnOnce(&mut Cache) {
-        clean_fake_home();
-        let cache_path = temp_directory();
+    /// Records a link and the directories created for it in the ledger.
+    fn record(&self, ledger: &mut Ledger, entry: ledger::Entry, created_directories: Vec<PathBuf>) {
+        for path in created_directories {
+            ledger.record_directory(ledger::Directory {
+                path,
+                user: self.username.clone(),
+                home: entry.home.clone(),
+            });
+        }
 
-        let mut cache = Cache::create(cache_path).expect("could not create cache");
-        f(&mut cache);
+        ledger.record(entry);
+    }
 
-        destroy_cache(cache);
-        clean_fake_home();
+    /// Builds a ledger entry for a link owned by this user.
+    fn ledger_entry(&self,
+                    path: PathBuf,
+                    target: PathBuf,
+                    symlink_config: &symlink::Config,
+                    revision: &Option<String>) -> ledger::Entry {
+        ledger::Entry {
+            path,
+            target,
+            user: self.username.clone(),
+            home: symlink_config.home_path.clone(),
+            revision: revision.clone(),
+            mode: ledger::Mode::Symlink,
+            checksum: None,
+        }
     }
 
-    /// Runs a function with a freshly-created user cache.
-    fn with_user_cache<F>(f: F)
-        where F: FnOnce(&mut UserCache) {
-        with_cache(|cache| {
-            let mut user_cache = cache.user("jenny");
-            f(&mut user_cache);
+    /// Loads everything needed to deploy dotfiles.
+    fn generators(&self, features: &FeatureSet) -> Result<Generators, Error> {
+        let identity_path = self.identity_path();
+        let config = self.repository_config()?;
+
+        Ok(Generators {
+            revision: self.revision()?,
+            modes: config.modes()?,
+            link_modes: config.link_modes()?,
+            root_path: config.root_path(&self.dotfiles_path())?,
+            context: template::Context::current(self.environment(), &self.username, features, &self.vars_path())?,
+            identity: if identity_path.exists() { Some(secret::Identity::load(&identity_path)?) } else { None },
         })
     }
 
-    /// Destroys a cache from disk.
-    fn destroy_cache(cache: Cache) {
-        fs::remove_dir_all(cache.path).expect("could not destroy cache");
-    }
+    /// Encrypts a file into the dotfiles repository.
+    ///
+    /// The file must live inside the home directory, and the encrypted copy is
+    /// placed wherever the repository's path mappings would deploy it from, and
+    /// staged so that it is a dotfile straight away. The original file is then
+    /// managed by polk as if it had been decrypted from the repository.
+    ///
+    /// A new identity is generated if the user does not have one yet.
+    pub fn encrypt(&mut self,
+                   path: &Path,
+                   symlink_config: &symlink::Config,
+                   wait: bool,
+                   verbose: bool) -> Result<PathBuf, Error> {
+        if !self.is_grabbed() {
+            bail!(ErrorKind::NotGrabbed(self.username.clone()));
+        }
 
-    /// Cleans the fake home directory.
-    fn clean_fake_home() {
-        let home = ::util::home_dir();
-        if home.exists() {
-            fs::remove_dir_all(&home).expect("could not clean fake home");
+        if util::is_inside(path, &self.dotfiles_path())? {
+            bail!("'{}' is inside the dotfiles repository, move it out before encrypting it", path.display());
         }
-        fs::create_dir_all(&home).ok();
-    }
 
-    /// Populates a repository with dotfiles.
-    fn populate_dotfiles_repository(repo: &git2::Repository,
-                                    dotfiles: &'static [(&'static str, &'static str)])  {
-        use std::fs::File;
-        let sig = repo.signature().unwrap();
+        let relative_path = match path.strip_prefix(&symlink_config.home_path) {
+            Ok(relative_path) => relative_path.to_owned(),
+            Err(..) => bail!("'{}' is not inside '{}'", path.display(), symlink_config.home_path.display()),
+        };
 
-        let tree_id = {
-            let mut index = repo.index().unwrap();
+        let identity_path = self.identity_path();
+        let identity = if identity_path.exists() {
+            secret::Identity::load(&identity_path)?
+        } else {
+            ilog!("generating a new identity at '{}', keep a backup of it somewhere safe", identity_path.display());
+            secret::Identity::generate(&identity_path)?
+        };
 
-            for &(file_name, content) in dotfiles.iter() {
-                let repo_dir = repo.workdir().unwrap();
-                let file_path = repo_dir.join(file_name);
-                let repo_relative_path = file_path.strip_prefix(&repo_dir).unwrap();
+        let plaintext = fs::read(path).chain_err(|| format!("could not read '{}'", path.display()))?;
+        let ciphertext = identity.encrypt(&plaintext)?;
 
-                let mut file = File::create(&file_path).unwrap();
-                file.write_all(content.as_bytes()).unwrap();
-                drop(file);
+        let config = self.repository_config()?;
+        let mut relative_encrypted_path = relative_path.into_os_string();
+        relative_encrypted_path.push(".");
+        relative_encrypted_path.push(secret::EXTENSION);
+
+        let encrypted_path = match config.mapper(self.environment())?.unmap(Path::new(&relative_encrypted_path)) {
+            Some(repository_path) => config.root_path(&self.dotfiles_path())?.join(repository_path),
+            None => bail!("no file in the dotfiles repository would be deployed to '{}'", path.display()),
+        };
+        let revision = self.revision()?;
 
-                index.add_path(&repo_relative_path).unwrap();
+        self.cache.change_ledger(wait, verbose, |ledger, journal| {
+            journal.track(&encrypted_path)?;
+
+            if let Some(parent) = encrypted_path.parent() {
+                fs::create_dir_all(parent)?;
             }
+            fs::write(&encrypted_path, ciphertext)?;
+            vlog!(verbose => "encrypted {} into {}", path.display(), encrypted_path.display());
 
-            index.write_tree().unwrap()
-        };
+            // Secrets should never be readable by anybody else.
+            journal.track(path)?;
+            fs::set_permissions(path, fs::Permissions::from_mode(secret::PERMISSIONS))?;
 
-        let tree = repo.find_tree(tree_id).unwrap();
+            let mut entry = self.ledger_entry(path.to_owned(), encrypted_path.clone(), symlink_config, &revision);
+            entry.mode = ledger::Mode::Decrypted;
+            entry.checksum = Some(util::checksum(&plaintext));
+            self.record(ledger, entry, Vec::new());
+            Ok(())
+        })?;
 
-        // Ready to create the initial commit.
-        //
-        // Normally creating a commit would involve looking up the current HEAD
-        // commit and making that be the parent of the initial commit, but here this
-        // is the first commit so there will be no parent.
-        repo.commit(Some("HEAD"), &sig, &sig, "Initial commit", &tree, &[]).unwrap();
-    }
+        let (_, backend) = self.open_manifest_backend()?;
+        backend.stage(repository_path(&encrypted_path, &self.dotfiles_path())).
+            chain_err(|| format!("could not stage '{}', add it to the repository yourself", encrypted_path.display()))?;
 
-    /// Gets the symlinking configuration.
-    fn symlink_config() -> symlink::Config {
-        symlink::Config {
-            home_path: ::util::home_dir(),
-        }
+        Ok(encrypted_path)
     }
 
-    /// Gets all dotfiles that have been symlinked.
-    fn symlinked_dotfiles(user_cache: &UserCache) -> Vec<Dotfile> {
-        let config = symlink_config();
+    /// Works out the state of every dotfile in a home directory.
+    ///
+    /// Links which polk created for dotfiles that have since been removed
+    /// from the repository are included if they are now dangling.
+    pub fn status(&self, dotfiles: &[Dotfile], symlink_config: &symlink::Config) -> Result<Vec<DotfileStatus>, Error> {
+        let features = FeatureSet::for_environment(self.environment())?;
+        let ledger = self.cache.ledger()?;
+        let mut statuses = Vec::new();
+
+        for mut dotfile in dotfiles.iter().cloned() {
+            let state = if features.supports(&dotfile) {
+                features.substitute_enabled_feature_names(&mut dotfile)?;
+                status::classify(&dotfile, symlink_config, ledger.entry(&symlink::path(&dotfile, symlink_config)))?
+            } else {
+                State::Excluded
+            };
 
-        user_cache.dotfiles().unwrap().into_iter().filter(|dotfile| {
-            symlink::exists(&dotfile, &config).unwrap()
-        }).collect()
-    }
+            statuses.push(DotfileStatus {
+                destination: symlink::path(&dotfile, symlink_config),
+                source: dotfile.full_path,
+                state,
+            });
+        }
 
-    #[test]
-    fn grab_works_as_expected() {
-        with_user_cache(|user_cache| {
-            assert!(user_cache.dotfiles().unwrap().is_empty(), "empty cache should not have dotfiles");
-            assert!(symlinked_dotfiles(&user_cache).is_empty(), "empty cache should have no symlinked dotfiles");
+        let leftover = ledger.links.iter().filter(|entry| {
+            entry.user == self.username && entry.home == symlink_config.home_path &&
+                entry.mode == ledger::Mode::Symlink && entry.target != self.dotfiles_path()
+        });
 
-            // Ensure that grabbing dotfiles gets all expected dotfiles.
-            user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
-            assert_eq!(user_cache.dotfiles().unwrap().len(), DOTFILES.len());
-            // assert_eq!(symlinked_dotfiles(&user_cache), &[], "grabbing should not create symlinks");
+        for entry in leftover {
+            if statuses.iter().any(|s| s.destination == entry.path) {
+                continue;
+            }
 
-            // Ensure file names are correct.
-            for dotfile in user_cache.dotfiles().unwrap() {
-                let does_match = DOTFILES.iter().any(|&(file_name,_)| dotfile.relative_path == Path::new(file_name));
-                assert!(does_match, "a file path in the cache does not match");
+            if entry.is_intact() && !entry.path.exists() {
+                statuses.push(DotfileStatus {
+                    source: entry.target.clone(),
+                    destination: entry.path.clone(),
+                    state: State::Dangling(entry.target.clone()),
+                });
             }
-        });
+        }
+
+        Ok(statuses)
     }
 
-    #[test]
-    fn link_unlink_leaves_nothing() {
+    /// Works out what linking would change in a home directory.
+    ///
+    /// Dotfiles which are already linked are skipped, as are paths where
+    /// there is nothing to replace. If any paths are given, only dotfiles
+    /// inside them are considered.
+    pub fn changes(&self, symlink_config: &symlink::Config, paths: &[PathBuf]) -> Result<Vec<diff::Change>, Error> {
+        let features = FeatureSet::for_environment(self.environment())?;
+        let generators = self.generators(&features)?;
+        let ledger = self.cache.ledger()?;
+        let mut changes = Vec::new();
+
+        for mut dotfile in self.dotfiles()? {
+            if !features.supports(&dotfile) {
+                continue;
+            }
+
+            features.substitute_enabled_feature_names(&mut dotfile)?;
+            let destination = symlink::path(&dotfile, symlink_config);
+
+            let is_selected = paths.is_empty() || paths.iter().any(|path| {
+                destination.starts_with(path) || dotfile.full_path.starts_with(path)
+            });
+
+            if !is_selected {
+                continue;
+            }
+
+            let state = status::classify(&dotfile, symlink_config, ledger.entry(&destination))?;
+
+            // Generated files and copies are compared even when they are up to
+            // date, as whatever they were written from may have changed since.
+            let is_generated = dotfile.kind == DotfileKind::Template || dotfile.kind == DotfileKind::Secret ||
+                self::is_copied(&dotfile, symlink_config, &generators);
+            let has_something_to_replace = match state {
+                State::Blocked { .. } | State::Replaced | State::Elsewhere(..) | State::Drifted => true,
+                State::Linked | State::Missing => is_generated,
+                State::Dangling(..) | State::Excluded => false,
+            };
+
+            if !has_something_to_replace {
+                continue;
+            }
+
+            let new = match dotfile.kind {
+                DotfileKind::Directory => {
+                    if destination.is_dir() {
+                        changes.extend(diff::directories(&destination, &dotfile.full_path)?);
+                    }
+                    continue;
+                },
+                DotfileKind::File => fs::read(&dotfile.full_path)?,
+                DotfileKind::Template => template::render_file(&dotfile.full_path, &generators.context)?.into_bytes(),
+                DotfileKind::Secret => match generators.identity {
+                    Some(ref identity) => identity.decrypt_file(&dotfile.full_path)?,
+                    None => {
+                        warn!("cannot compare '{}' without an identity at '{}'",
+                              dotfile.full_path.display(), self.identity_path().display());
+                        continue;
+                    },
+                },
+            };
+
+            let change = diff::Change {
+                old: if destination.is_dir() { None } else { diff::read(&destination)? },
+                new: Some(new),
+                old_path: destination,
+                new_path: dotfile.full_path,
+                hidden: dotfile.kind == DotfileKind::Secret,
+            };
+
+            if change.is_change() {
+                changes.push(change);
+            }
+        }
+
+        Ok(changes)
+    }
+
+    /// Finds the links polk created which have since been replaced with regular files.
+    pub fn replaced_links(&self, symlink_config: &symlink::Config) -> Result<Vec<ledger::Entry>, Error> {
+        let ledger = self.cache.ledger()?;
+
+        Ok(ledger.links.into_iter().filter(|entry| {
+            let is_replaced = fs::symlink_metadata(&entry.path).map(|m| m.is_file()).unwrap_or(false);
+
+            entry.user == self.username && entry.home == symlink_config.home_path &&
+                entry.mode == ledger::Mode::Symlink && entry.target.is_file() && is_replaced
+        }).collect())
+    }
+
+    /// Copies a file that replaced a link back into the repository, then restores the link.
+    pub fn readopt(&self, entry: &ledger::Entry, wait: bool, verbose: bool) -> Result<(), Error> {
+        let symlink_config = symlink::Config::with_home(entry.home.clone());
+        let revision = self.revision()?;
+
+        self.cache.change_ledger(wait, verbose, |ledger, journal| {
+            let contents = fs::read(&entry.path)?;
+
+            journal.track(&entry.target)?;
+            fs::write(&entry.target, contents)?;
+            vlog!(verbose => "copied {} into {}", entry.path.display(), entry.target.display());
+
+            journal.track(&entry.path)?;
+            fs::remove_file(&entry.path)?;
+            sym::symlink_file(&entry.target, &entry.path)?;
+            vlog!(verbose => "created {} -> {}", entry.target.display(), entry.path.display());
+
+            let entry = self.ledger_entry(entry.path.clone(), entry.target.clone(), &symlink_config, &revision);
+            self.record(ledger, entry, Vec::new());
+            Ok(())
+        })
+    }
+
+    /// Gets the state of the dotfiles repository.
+    pub fn repository_status(&self) -> Result<RepositoryStatus, Error> {
+        let (_, backend) = self.open_manifest_backend()?;
+        backend.status()
+    }
+
+    /// Gets the currently checked out revision of the dotfiles, if grabbed.
+    pub fn revision(&self) -> Result<Option<String>, Error> {
+        if !self.is_grabbed() {
+            return Ok(None);
+        }
+
+        let (_, backend) = self.open_manifest_backend()?;
+        backend.revision()
+    }
+
+    /// Gets all of the dotfiles in the cache.
+    pub fn dotfiles(&self) -> Result<Vec<Dotfile>, Error> {
+        let mut dotfiles = Vec::new();
+        let dotfiles_path = self.dotfiles_path();
+
+        if !dotfiles_path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let config = self.repository_config()?;
+        let mapper = config.mapper(self.environment())?;
+        let ignored = config.ignored()?;
+        let root_path = config.root_path(&dotfiles_path)?;
+        let mut ignores = polkignore::Ignores::new(&root_path)?;
+
+        // Ask the backend once, rather than looking for `.git` above every file.
+        let (_, backend) = self.open_manifest_backend()?;
+        let submodules: BTreeSet<PathBuf> = backend.submodules()?.into_iter().collect();
+        let managed = if config.include_untracked {
+            None
+        } else {
+            backend.managed_files()?.map(self::with_ancestors)
+        };
+
+        // Anything that can never hold a dotfile is pruned, so that the walk
+        // does not descend into `.git` or vendored submodules at all.
+        let mut walker = WalkDir::new(&root_path).into_iter().filter_entry(|entry| {
+            if entry.depth() == 0 {
+                return true;
+            }
+
+            let file_name = entry.file_name();
+            let path = self::repository_path(entry.path(), &dotfiles_path);
+            let is_blacklisted = if entry.file_type().is_dir() {
+                DIRECTORY_BLACKLIST.iter().any(|&bl| file_name == bl)
+            } else {
+                DOTFILE_FILE_BLACKLIST.iter().any(|&bl| file_name == bl)
+            };
+            let is_managed = match managed {
+                Some(ref managed) => managed.contains(path),
+                // Untracked directories can be whole repositories of their own,
+                // which the backend knows nothing about.
+                None => !(entry.file_type().is_dir() && entry.path().join(".git").exists()),
+            };
+
+            !is_blacklisted && is_managed && !submodules.contains(path)
+        });
+
+        while let Some(entry) = walker.next() {
+            let entry = entry?;
+            let repository_path = self::repository_path(entry.path(), &root_path);
+
+            if ignored.is_ignored(repository_path) || ignores.is_ignored(&entry)? {
+                if entry.file_type().is_dir() {
+                    walker.skip_current_dir();
+                }
+                continue;
+            }
+
+            // Symlinks inside the repository are treated like whatever they point to.
+            let file_type = if entry.path_is_symlink() {
+                match fs::metadata(entry.path()) {
+                    Ok(metadata) => metadata.file_type(),
+                    Err(..) => continue,
+                }
+            } else {
+                entry.file_type()
+            };
+
+            // Directories containing the marker file are linked as a whole,
+            // so there is no need to look at anything inside them.
+            let kind = if entry.depth() > 0 && file_type.is_dir() &&
+                entry.path().join(LINK_DIRECTORY_MARKER).is_file() {
+                walker.skip_current_dir();
+                DotfileKind::Directory
+            } else if file_type.is_file() {
+                if template::is_template(entry.path()) {
+                    DotfileKind::Template
+                } else if secret::is_secret(entry.path()) {
+                    DotfileKind::Secret
+                } else {
+                    DotfileKind::File
+                }
+            } else {
+                continue;
+            };
+
+            let mut relative_path = mapper.map(repository_path);
+
+            // Generated files are named after the template or secret without its extension.
+            if kind == DotfileKind::Template || kind == DotfileKind::Secret {
+                relative_path.set_extension("");
+            }
+
+            dotfiles.push(Dotfile {
+                full_path: entry.path().to_owned(),
+                relative_path,
+                kind,
+            });
+        }
+
+        Ok(dotfiles)
+    }
+
+    /// Gets the configuration stored inside the dotfiles repository.
+    pub fn repository_config(&self) -> Result<repository::Config, Error> {
+        repository::Config::load(&self.dotfiles_path())
+    }
+
+    /// Gets the directory inside the dotfiles repository that holds the dotfiles.
+    pub fn root_path(&self) -> Result<PathBuf, Error> {
+        self.repository_config()?.root_path(&self.dotfiles_path())
+    }
+
+    /// Gets the manifest.
+    pub fn manifest(&self) -> Result<UserManifest, Error> {
+        UserManifest::load(&self.manifest_path()).chain_err(|| "reading user manifest")
+    }
+
+    /// Gets the manifest and backend.
+    fn open_manifest_backend(&self) -> Result<(UserManifest, Box<dyn Backend>), Error> {
+        let manifest = self.manifest()?;
+        backend::open(&self.dotfiles_path(), manifest.source.clone()).map(|b| (manifest, b))
+    }
+}
+
+/// Gets the path of a file relative to the root of the dotfiles.
+fn repository_path<'a>(path: &'a Path, root_path: &Path) -> &'a Path {
+    path.strip_prefix(root_path).unwrap_or(path)
+}
+
+/// Adds every directory that holds one of a set of files to it.
+fn with_ancestors(files: BTreeSet<PathBuf>) -> BTreeSet<PathBuf> {
+    let mut paths = BTreeSet::new();
+
+    for file in files {
+        for ancestor in file.ancestors().skip(1) {
+            // Directories holding several files only need adding once, and so do their parents.
+            if ancestor.as_os_str().is_empty() || !paths.insert(ancestor.to_owned()) {
+                break;
+            }
+        }
+        paths.insert(file);
+    }
+
+    paths
+}
+
+/// Checks if a dotfile should be copied rather than symlinked.
+///
+/// The repository can choose how particular paths are deployed, which takes
+/// precedence over the mode the dotfiles are being linked with.
+fn is_copied(dotfile: &Dotfile, symlink_config: &symlink::Config, generators: &Generators) -> bool {
+    let repository_path = self::repository_path(&dotfile.full_path, &generators.root_path);
+    let mode = generators.link_modes.mode(repository_path).unwrap_or(symlink_config.mode);
+
+    mode == symlink::Mode::Copy && dotfile.kind == DotfileKind::File
+}
+
+/// Lists every path in some dotfiles that has a declared mode.
+///
+/// Files keep whatever executable bit Git has recorded for them.
+fn declared_modes(dotfiles: &[Dotfile], modes: &permissions::Modes, root_path: &Path) -> Result<Vec<(PathBuf, u32)>, Error> {
+    let mut declared = BTreeMap::new();
+
+    for dotfile in dotfiles {
+        if dotfile.kind != DotfileKind::Directory {
+            if let Some(mode) = modes.file_mode(self::repository_path(&dotfile.full_path, root_path)) {
+                let mode = permissions::tracked_mode(&dotfile.full_path, mode)?;
+                declared.insert(dotfile.full_path.clone(), mode);
+            }
+            continue;
+        }
+
+        // Linked directories are exposed as-is, so everything inside needs the right mode,
+        // including the directories holding matching files.
+        for entry in WalkDir::new(&dotfile.full_path).min_depth(1) {
+            let entry = entry?;
+            let repository_path = self::repository_path(entry.path(), root_path);
+
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            if let Some(mode) = modes.file_mode(repository_path) {
+                declared.insert(entry.path().to_owned(), permissions::tracked_mode(entry.path(), mode)?);
+            }
+
+            if let Some(mode) = modes.directory_mode(repository_path) {
+                let parents = entry.path().ancestors().skip(1).take(entry.depth());
+                declared.extend(parents.map(|parent| (parent.to_owned(), mode)));
+            }
+        }
+    }
+
+    Ok(declared.into_iter().collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use SourceSpec;
+    use symlink;
+    use testing::{temp_directory, with_cache};
+
+    use git2;
+
+    use std::path::PathBuf;
+    use std::io::prelude::*;
+
+    lazy_static! {
+        /// A path which contains a git repository with dotfiles.
+        static ref DOTFILES_REPO_PATH: PathBuf = {
+            let path = temp_directory("dotfiles");
+
+            let repo = git2::Repository::init(&path).expect("failed to create repository");
+            self::populate_dotfiles_repository(&repo, DOTFILES);
+            path
+        };
+
+        static ref DOTFILES_SOURCE: SourceSpec = {
+            let url = format!("file://{}", DOTFILES_REPO_PATH.display());
+            SourceSpec::Url(url)
+        };
+    }
+
+    /// Example dotfiles.
+    const DOTFILES: &[(&str, &str)] = &[
+        (".vimrc", "set ruler\nset sw=2\nset ts=2\n\n"),
+        (".bashrc", "export PATH=/foo/bar:$PATH"),
+    ];
+
+    /// Runs a function with a freshly-created user cache.
+    fn with_user_cache<F>(f: F)
+        where F: FnOnce(&mut UserCache) {
+        with_cache(|cache| {
+            let mut user_cache = cache.user("jenny");
+            f(&mut user_cache);
+        })
+    }
+
+    /// Populates a repository with dotfiles.
+    fn populate_dotfiles_repository(repo: &git2::Repository,
+                                    dotfiles: &'static [(&'static str, &'static str)])  {
+        use std::fs::File;
+        let sig = git2::Signature::now("Jenny", "jenny@example.com").unwrap();
+
+        let tree_id = {
+            let mut index = repo.index().unwrap();
+
+            for &(file_name, content) in dotfiles.iter() {
+                let repo_dir = repo.workdir().unwrap();
+                let file_path = repo_dir.join(file_name);
+                let repo_relative_path = file_path.strip_prefix(repo_dir).unwrap();
+
+                let mut file = File::create(&file_path).unwrap();
+                file.write_all(content.as_bytes()).unwrap();
+                drop(file);
+
+                index.add_path(repo_relative_path).unwrap();
+            }
+
+            index.write_tree().unwrap()
+        };
+
+        let tree = repo.find_tree(tree_id).unwrap();
+
+        // Ready to create the initial commit.
+        //
+        // Normally creating a commit would involve looking up the current HEAD
+        // commit and making that be the parent of the initial commit, but here this
+        // is the first commit so there will be no parent.
+        repo.commit(Some("HEAD"), &sig, &sig, "Initial commit", &tree, &[]).unwrap();
+    }
+
+    /// Gets all dotfiles that have been symlinked.
+    fn symlinked_dotfiles(user_cache: &UserCache) -> Vec<Dotfile> {
+        let config = symlink::Config::new(user_cache.environment());
+
+        user_cache.dotfiles().unwrap().into_iter().filter(|dotfile| {
+            symlink::exists(dotfile, &config).unwrap()
+        }).collect()
+    }
+
+    #[test]
+    fn grab_works_as_expected() {
         with_user_cache(|user_cache| {
-            assert_eq!(symlinked_dotfiles(&user_cache), &[]);
+            assert!(user_cache.dotfiles().unwrap().is_empty(), "empty cache should not have dotfiles");
+            assert!(symlinked_dotfiles(user_cache).is_empty(), "empty cache should have no symlinked dotfiles");
+
             // Ensure that grabbing dotfiles gets all expected dotfiles.
             user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
             assert_eq!(user_cache.dotfiles().unwrap().len(), DOTFILES.len());
-            // assert_eq!(symlinked_dotfiles(&user_cache), &[]);
+            // assert_eq!(symlinked_dotfiles(user_cache), &[], "grabbing should not create symlinks");
+
+            // Ensure file names are correct.
+            for dotfile in user_cache.dotfiles().unwrap() {
+                let does_match = DOTFILES.iter().any(|&(file_name,_)| dotfile.relative_path == Path::new(file_name));
+                assert!(does_match, "a file path in the cache does not match");
+            }
+        });
+    }
+
+    #[test]
+    fn untracked_files_are_not_dotfiles() {
+        with_user_cache(|user_cache| {
+            user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
+            fs::write(user_cache.dotfiles_path().join(".vimrc.swp"), "junk").unwrap();
+            assert_eq!(user_cache.dotfiles().unwrap().len(), DOTFILES.len());
 
-            user_cache.link(false).unwrap();
-            assert_eq!(symlinked_dotfiles(&user_cache).len(), DOTFILES.len(), "all symlinks should be created");
-            user_cache.unlink(false).unwrap();
-            assert!(symlinked_dotfiles(&user_cache).is_empty(), "all symlinks should be destroyed");
+            fs::write(user_cache.dotfiles_path().join(repository::CONFIG_FILE_NAME), "include_untracked = true").unwrap();
+            assert_eq!(user_cache.dotfiles().unwrap().len(), DOTFILES.len() + 1);
+        });
+    }
+
+    #[test]
+    fn pinned_dotfiles_stay_checked_out_until_unpinned() {
+        with_user_cache(|user_cache| {
+            user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
+            let revision = user_cache.revision().unwrap().unwrap();
+            let branch = user_cache.repository_status().unwrap().branch;
+            assert!(branch.is_some());
+
+            user_cache.pin(&revision, false).unwrap();
+            assert_eq!(user_cache.manifest().unwrap().pinned_ref.as_ref(), Some(&revision));
+            assert_eq!(user_cache.repository_status().unwrap().branch, None, "the revision should be checked out");
+            user_cache.update(true, false).unwrap();
+            assert_eq!(user_cache.revision().unwrap(), Some(revision.clone()));
+
+            user_cache.unpin(false).unwrap();
+            assert_eq!(user_cache.manifest().unwrap().pinned_ref, None);
+            assert_eq!(user_cache.repository_status().unwrap().branch, branch);
+            assert!(user_cache.pin("no-such-ref", false).is_err());
+        });
+    }
+
+    #[test]
+    fn link_unlink_leaves_nothing() {
+        with_user_cache(|user_cache| {
+            assert_eq!(symlinked_dotfiles(user_cache), &[]);
+            // Ensure that grabbing dotfiles gets all expected dotfiles.
+            user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
+            assert_eq!(user_cache.dotfiles().unwrap().len(), DOTFILES.len());
+            // assert_eq!(symlinked_dotfiles(user_cache), &[]);
+
+            user_cache.link(true, false).unwrap();
+            assert_eq!(symlinked_dotfiles(user_cache).len(), DOTFILES.len(), "all symlinks should be created");
+            user_cache.unlink(true, false).unwrap();
+            assert!(symlinked_dotfiles(user_cache).is_empty(), "all symlinks should be destroyed");
+        });
+    }
+
+    #[test]
+    fn link_is_recorded_in_manifest() {
+        with_user_cache(|user_cache| {
+            user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
+            user_cache.link(true, false).unwrap();
+
+            let manifest = user_cache.manifest().unwrap();
+            assert_eq!(manifest.last_linked_revision, user_cache.revision().unwrap());
+            assert_eq!(manifest.target.as_ref(), Some(&user_cache.environment().home_path));
+            assert_eq!(manifest.link_mode, Some(symlink::Mode::Symlink));
         });
     }
 
     #[test]
     fn link_without_grab_does_nothing() {
         with_user_cache(|user_cache| {
-            user_cache.link(false).unwrap();
-            assert!(symlinked_dotfiles(&user_cache).is_empty(), "no symlinks should exist");
+            user_cache.link(true, false).unwrap();
+            assert!(symlinked_dotfiles(user_cache).is_empty(), "no symlinks should exist");
         });
     }
 
     #[test]
     fn setup_creates_symlinks() {
         with_user_cache(|user_cache| {
-            assert!(symlinked_dotfiles(&user_cache).is_empty(), "empty cache should have no symlinked dotfiles");
+            assert!(symlinked_dotfiles(user_cache).is_empty(), "empty cache should have no symlinked dotfiles");
 
             // Ensure that grabbing dotfiles gets all expected dotfiles.
-            user_cache.setup(&DOTFILES_SOURCE, false).unwrap();
+            user_cache.setup(&DOTFILES_SOURCE, true, false).unwrap();
             assert_eq!(user_cache.dotfiles().unwrap().len(), DOTFILES.len());
-            assert_eq!(symlinked_dotfiles(&user_cache).len(), DOTFILES.len(),  "setup should create symlinks");
+            assert_eq!(symlinked_dotfiles(user_cache).len(), DOTFILES.len(),  "setup should create symlinks");
 
             // Ensure file names are correct.
             for dotfile in user_cache.dotfiles().unwrap() {
@@ -571,5 +1512,455 @@ mod test {
             }
         });
     }
-}
 
+    #[test]
+    fn copies_are_only_removed_while_unmodified() {
+        with_user_cache(|user_cache| {
+            user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
+
+            let mut config = symlink::Config::new(user_cache.environment());
+            config.mode = symlink::Mode::Copy;
+            user_cache.link_ext(&config, true, false).unwrap();
+
+            let vimrc_path = config.home_path.join(".vimrc");
+            let bashrc_path = config.home_path.join(".bashrc");
+            assert!(fs::symlink_metadata(&vimrc_path).unwrap().file_type().is_file(), "copies should be regular files");
+            assert_eq!(fs::read_to_string(&vimrc_path).unwrap(), DOTFILES[0].1);
+
+            fs::write(&bashrc_path, "modified").unwrap();
+            user_cache.unlink_ext(&config, true, false).unwrap();
+
+            assert!(!vimrc_path.exists(), "unmodified copies should be removed");
+            assert_eq!(fs::read_to_string(&bashrc_path).unwrap(), "modified", "modified copies should be left alone");
+        });
+    }
+
+    /// Links the dotfiles with a regular file in the way of `.bashrc`.
+    fn link_over_file(user_cache: &mut UserCache,
+                      conflicts: symlink::Conflicts) -> (symlink::Config, Result<(), Error>) {
+        user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
+
+        let mut config = symlink::Config::new(user_cache.environment());
+        config.conflicts = conflicts;
+        fs::write(config.home_path.join(".bashrc"), "mine").unwrap();
+
+        let result = user_cache.link_ext(&config, true, false);
+        (config, result)
+    }
+
+    #[test]
+    fn skipped_conflicts_are_left_alone() {
+        with_user_cache(|user_cache| {
+            let (config, result) = link_over_file(user_cache, symlink::Conflicts::Skip);
+            result.unwrap();
+
+            assert_eq!(fs::read_to_string(config.home_path.join(".bashrc")).unwrap(), "mine");
+            assert!(fs::symlink_metadata(config.home_path.join(".vimrc")).unwrap().file_type().is_symlink());
+        });
+    }
+
+    #[test]
+    fn backed_up_conflicts_are_moved_aside() {
+        with_user_cache(|user_cache| {
+            let (config, result) = link_over_file(user_cache, symlink::Conflicts::Backup);
+            result.unwrap();
+
+            assert!(fs::symlink_metadata(config.home_path.join(".bashrc")).unwrap().file_type().is_symlink());
+            assert_eq!(fs::read_to_string(config.home_path.join(".bashrc.polk-backup")).unwrap(), "mine");
+        });
+    }
+
+    #[test]
+    fn overwritten_conflicts_are_replaced() {
+        with_user_cache(|user_cache| {
+            let (config, result) = link_over_file(user_cache, symlink::Conflicts::Overwrite);
+            result.unwrap();
+
+            assert!(fs::symlink_metadata(config.home_path.join(".bashrc")).unwrap().file_type().is_symlink());
+            assert!(!config.home_path.join(".bashrc.polk-backup").exists(), "overwriting should not back up");
+        });
+    }
+
+    #[test]
+    fn failed_conflicts_roll_back_everything() {
+        with_user_cache(|user_cache| {
+            let (config, result) = link_over_file(user_cache, symlink::Conflicts::Fail);
+
+            match result {
+                Err(Error(ErrorKind::LinkConflict(path, _), _)) => assert_eq!(path, config.home_path.join(".bashrc")),
+                other => panic!("expected a link conflict, got {:?}", other),
+            }
+            assert_eq!(fs::read_to_string(config.home_path.join(".bashrc")).unwrap(), "mine");
+            assert!(fs::symlink_metadata(config.home_path.join(".vimrc")).is_err(), "links should be rolled back");
+        });
+    }
+
+    /// Links dotfiles over somebody else's symlink with a conflict policy.
+    fn link_over_symlink(user_cache: &mut UserCache,
+                         conflicts: symlink::Conflicts) -> (symlink::Config, PathBuf, Result<(), Error>) {
+        user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
+
+        let mut config = symlink::Config::new(user_cache.environment());
+        config.conflicts = conflicts;
+        let theirs = config.home_path.join("their-bashrc");
+        fs::write(&theirs, "theirs").unwrap();
+        unix::fs::symlink(&theirs, config.home_path.join(".bashrc")).unwrap();
+
+        let result = user_cache.link_ext(&config, true, false);
+        (config, theirs, result)
+    }
+
+    #[test]
+    fn skipped_symlinks_are_left_alone() {
+        with_user_cache(|user_cache| {
+            let (config, theirs, result) = link_over_symlink(user_cache, symlink::Conflicts::Skip);
+            result.unwrap();
+
+            assert_eq!(fs::read_link(config.home_path.join(".bashrc")).unwrap(), theirs);
+            assert!(fs::symlink_metadata(config.home_path.join(".vimrc")).unwrap().file_type().is_symlink());
+        });
+    }
+
+    #[test]
+    fn failed_symlink_conflicts_roll_back_everything() {
+        with_user_cache(|user_cache| {
+            let (config, theirs, result) = link_over_symlink(user_cache, symlink::Conflicts::Fail);
+
+            match result {
+                Err(Error(ErrorKind::LinkConflict(path, "symlink"), _)) => assert_eq!(path, config.home_path.join(".bashrc")),
+                other => panic!("expected a symlink conflict, got {:?}", other),
+            }
+            assert_eq!(fs::read_link(config.home_path.join(".bashrc")).unwrap(), theirs);
+            assert!(fs::symlink_metadata(config.home_path.join(".vimrc")).is_err(), "links should be rolled back");
+        });
+    }
+
+    #[test]
+    fn backed_up_symlinks_keep_their_target() {
+        with_user_cache(|user_cache| {
+            let (config, theirs, result) = link_over_symlink(user_cache, symlink::Conflicts::Backup);
+            result.unwrap();
+
+            assert_eq!(fs::read_link(config.home_path.join(".bashrc.polk-backup")).unwrap(), theirs);
+            assert!(fs::read_link(config.home_path.join(".bashrc")).unwrap().starts_with(user_cache.dotfiles_path()));
+        });
+    }
+
+    #[test]
+    fn error_kinds_survive_added_context() {
+        with_user_cache(|user_cache| {
+            let mut config = symlink::Config::new(user_cache.environment());
+            config.conflicts = symlink::Conflicts::Fail;
+            fs::write(config.home_path.join(".bashrc"), "mine").unwrap();
+
+            match *user_cache.setup_ext(&DOTFILES_SOURCE, &config, true, false).unwrap_err().specific_kind() {
+                ErrorKind::LinkConflict(ref path, _) => assert_eq!(path, &config.home_path.join(".bashrc")),
+                ref other => panic!("expected a link conflict, got {:?}", other),
+            }
+
+            fs::remove_file(config.home_path.join(".bashrc")).unwrap();
+            fs::write(user_cache.manifest_path(), "not a manifest").unwrap();
+
+            match *user_cache.link_ext(&config, true, false).unwrap_err().specific_kind() {
+                ErrorKind::ManifestParse(ref path) => assert_eq!(path, &user_cache.manifest_path()),
+                ref other => panic!("expected the manifest to be unparseable, got {:?}", other),
+            }
+        });
+    }
+
+    #[test]
+    fn marked_directories_are_linked_whole() {
+        with_user_cache(|user_cache| {
+            user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
+
+            let nvim_path = user_cache.dotfiles_path().join(".config/nvim");
+            fs::create_dir_all(nvim_path.join("lua")).unwrap();
+            fs::write(nvim_path.join(LINK_DIRECTORY_MARKER), "").unwrap();
+            fs::write(nvim_path.join("lua/init.lua"), "").unwrap();
+            fs::write(user_cache.dotfiles_path().join(repository::CONFIG_FILE_NAME), "include_untracked = true").unwrap();
+
+            let dotfiles = user_cache.dotfiles().unwrap();
+            let directory = dotfiles.iter().find(|dotfile| dotfile.relative_path == Path::new(".config/nvim"))
+                .expect("the marked directory should be a dotfile");
+            assert_eq!(directory.kind, DotfileKind::Directory);
+            assert_eq!(dotfiles.len(), DOTFILES.len() + 1, "files inside the directory should not be dotfiles");
+
+            user_cache.link(true, false).unwrap();
+            let home_path = &user_cache.environment().home_path;
+            assert!(fs::symlink_metadata(home_path.join(".config/nvim")).unwrap().file_type().is_symlink());
+            assert!(home_path.join(".config/nvim/lua/init.lua").is_file());
+        });
+    }
+
+    #[test]
+    fn links_made_before_the_ledger_are_adopted() {
+        with_user_cache(|user_cache| {
+            user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
+
+            // Link the dotfiles the way polk did before it kept a ledger.
+            let home_path = user_cache.environment().home_path.clone();
+            for dotfile in user_cache.dotfiles().unwrap() {
+                unix::fs::symlink(&dotfile.full_path, home_path.join(&dotfile.relative_path)).unwrap();
+            }
+            unix::fs::symlink("/etc/hostname", home_path.join(".profile")).unwrap();
+            assert!(!user_cache.cache.ledger_path().exists());
+
+            user_cache.unlink(true, false).unwrap();
+            assert!(symlinked_dotfiles(user_cache).is_empty(), "adopted links should be removed");
+            assert!(fs::symlink_metadata(home_path.join(".profile")).is_ok(), "other links should be left alone");
+        });
+    }
+
+    #[test]
+    fn mapped_dotfiles_stay_inside_the_target() {
+        with_user_cache(|user_cache| {
+            user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
+            fs::write(user_cache.dotfiles_path().join(repository::CONFIG_FILE_NAME),
+                      "[[map]]\nfrom = \".vimrc\"\nto = \"~/.config/vim/vimrc\"\n").unwrap();
+
+            let target_path = user_cache.environment().home_path.join("scratch");
+            let config = symlink::Config::with_home(target_path.clone());
+            user_cache.link_ext(&config, true, false).unwrap();
+
+            assert!(fs::symlink_metadata(target_path.join(".config/vim/vimrc")).unwrap().file_type().is_symlink());
+            assert!(!user_cache.environment().home_path.join(".config").exists());
+        });
+    }
+
+    #[test]
+    fn encrypted_files_are_staged_at_their_mapped_path() {
+        with_user_cache(|user_cache| {
+            user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
+            fs::write(user_cache.dotfiles_path().join(repository::CONFIG_FILE_NAME), "dot_prefix = true").unwrap();
+
+            let config = symlink::Config::new(user_cache.environment());
+            let key_path = config.home_path.join(".ssh/id_ed25519");
+            fs::create_dir_all(key_path.parent().unwrap()).unwrap();
+            fs::write(&key_path, "hunter2").unwrap();
+
+            let encrypted_path = user_cache.encrypt(&key_path, &config, true, false).unwrap();
+            assert_eq!(encrypted_path, user_cache.dotfiles_path().join("dot-ssh/id_ed25519.age"));
+
+            let secret = user_cache.dotfiles().unwrap().into_iter().find(|dotfile| dotfile.kind == DotfileKind::Secret)
+                .expect("the encrypted file should be a dotfile once staged");
+            assert_eq!(secret.relative_path, Path::new(".ssh/id_ed25519"));
+
+            let identity = secret::Identity::load(&user_cache.identity_path()).unwrap();
+            assert_eq!(identity.decrypt_file(&encrypted_path).unwrap(), b"hunter2");
+        });
+    }
+
+    #[test]
+    fn files_inside_the_repository_are_not_encrypted() {
+        with_user_cache(|user_cache| {
+            user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
+            let config = symlink::Config::new(user_cache.environment());
+            let vimrc_path = user_cache.dotfiles_path().join(".vimrc");
+
+            let error = user_cache.encrypt(&vimrc_path, &config, true, false).unwrap_err();
+            assert!(error.to_string().contains("inside the dotfiles repository"), "unexpected error: {}", error);
+            assert!(!user_cache.dotfiles_path().join(".vimrc.age").exists());
+        });
+    }
+
+    #[test]
+    fn modes_leave_the_worktree_clean() {
+        use std::os::unix::fs::PermissionsExt;
+
+        with_user_cache(|user_cache| {
+            user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
+            fs::write(user_cache.dotfiles_path().join(repository::CONFIG_FILE_NAME),
+                      "[[mode]]\npath = \".vimrc\"\nfile = \"700\"\n").unwrap();
+            user_cache.link(true, false).unwrap();
+
+            let mode = fs::metadata(user_cache.dotfiles_path().join(".vimrc")).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600, "the executable bit git recorded should be kept");
+
+            let dirty = user_cache.repository_status().unwrap().dirty;
+            assert_eq!(dirty, &[PathBuf::from(repository::CONFIG_FILE_NAME)], "only .polk.toml should be uncommitted");
+        });
+    }
+
+    #[test]
+    fn failed_links_restore_modes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        with_user_cache(|user_cache| {
+            user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
+            fs::write(user_cache.dotfiles_path().join(repository::CONFIG_FILE_NAME),
+                      "[[mode]]\npath = \".vimrc\"\nfile = \"600\"\n").unwrap();
+            let vimrc_path = user_cache.dotfiles_path().join(".vimrc");
+            fs::set_permissions(&vimrc_path, fs::Permissions::from_mode(0o644)).unwrap();
+
+            let mut config = symlink::Config::new(user_cache.environment());
+            config.conflicts = symlink::Conflicts::Fail;
+            fs::write(config.home_path.join(".bashrc"), "mine").unwrap();
+
+            assert!(user_cache.link_ext(&config, true, false).is_err());
+            assert_eq!(fs::metadata(&vimrc_path).unwrap().permissions().mode() & 0o777, 0o644,
+                       "the old mode should be put back");
+        });
+    }
+
+    #[test]
+    fn failed_relinks_keep_the_old_links() {
+        with_user_cache(|user_cache| {
+            user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
+            user_cache.link(true, false).unwrap();
+
+            let mut config = symlink::Config::new(user_cache.environment());
+            config.conflicts = symlink::Conflicts::Fail;
+            fs::remove_file(config.home_path.join(".bashrc")).unwrap();
+            fs::write(config.home_path.join(".bashrc"), "mine").unwrap();
+
+            assert!(user_cache.relink(&config, true, false).is_err());
+            assert!(fs::symlink_metadata(config.home_path.join(".vimrc")).unwrap().file_type().is_symlink(),
+                    "the old links should be put back");
+            assert_eq!(fs::read_to_string(config.home_path.join(".bashrc")).unwrap(), "mine");
+        });
+    }
+
+    #[test]
+    fn replaced_links_are_readopted() {
+        with_user_cache(|user_cache| {
+            user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
+            user_cache.link(true, false).unwrap();
+
+            // Programs that save their configuration often replace the symlink with a file.
+            let config = symlink::Config::new(user_cache.environment());
+            let vimrc_path = config.home_path.join(".vimrc");
+            fs::remove_file(&vimrc_path).unwrap();
+            fs::write(&vimrc_path, "set number").unwrap();
+
+            let replaced = user_cache.replaced_links(&config).unwrap();
+            assert_eq!(replaced.len(), 1);
+            assert_eq!(replaced[0].path, vimrc_path);
+
+            user_cache.readopt(&replaced[0], true, false).unwrap();
+            assert_eq!(fs::read_to_string(user_cache.dotfiles_path().join(".vimrc")).unwrap(), "set number");
+            assert_eq!(fs::read_link(&vimrc_path).unwrap(), user_cache.dotfiles_path().join(".vimrc"));
+            assert!(user_cache.replaced_links(&config).unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn names_that_are_not_utf8_are_not_linked() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        with_user_cache(|user_cache| {
+            user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
+            fs::write(user_cache.dotfiles_path().join(OsStr::from_bytes(b".vim\xff.linux")), "").unwrap();
+            fs::write(user_cache.dotfiles_path().join(repository::CONFIG_FILE_NAME), "include_untracked = true").unwrap();
+
+            user_cache.link(true, false).unwrap();
+            assert_eq!(symlinked_dotfiles(user_cache).len(), DOTFILES.len());
+        });
+    }
+
+    #[test]
+    fn caches_without_a_ledger_are_migrated() {
+        with_cache(|cache| {
+            cache.user("jenny").grab(&DOTFILES_SOURCE, false).unwrap();
+
+            // Link the dotfiles the way polk did before it kept a ledger.
+            let home_path = cache.environment.home_path.clone();
+            let relative_paths: Vec<_> = cache.user("jenny").dotfiles().unwrap().into_iter().map(|dotfile| {
+                unix::fs::symlink(&dotfile.full_path, home_path.join(&dotfile.relative_path)).unwrap();
+                dotfile.relative_path
+            }).collect();
+            assert!(!cache.ledger_path().exists());
+
+            let new_path = cache.path.with_file_name("moved");
+            let old_cache = Cache::open(cache.environment.clone()).unwrap();
+            let new_cache = old_cache.migrate(&new_path, false).unwrap();
+
+            assert!(!cache.path.exists());
+            assert_eq!(new_cache.ledger().unwrap().links.len(), relative_paths.len());
+            for relative_path in relative_paths {
+                let target = fs::read_link(home_path.join(&relative_path)).unwrap();
+                assert!(target.starts_with(&new_path), "'{}' still points to '{}'", relative_path.display(), target.display());
+                assert!(home_path.join(&relative_path).exists());
+            }
+        });
+    }
+
+    #[test]
+    fn ledger_changes_fail_when_locked_without_waiting() {
+        with_cache(|cache| {
+            let _lock = lock::acquire(&cache.path.join("ledger.lock"), lock::Kind::Exclusive, false).unwrap();
+
+            match cache.change_ledger(false, false, |_, _| Ok(())) {
+                Err(Error(ErrorKind::Locked(..), _)) => (),
+                other => panic!("expected the ledger to be locked, got {:?}", other),
+            }
+            assert!(!cache.ledger_path().exists());
+        });
+    }
+
+    #[test]
+    fn hooks_only_run_for_your_own_dotfiles_when_enabled() {
+        with_cache(|cache| {
+            for username in &["jenny", "bob"] {
+                let mut user_cache = cache.user(*username);
+                user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
+                fs::write(user_cache.dotfiles_path().join(repository::CONFIG_FILE_NAME),
+                          "[hooks]\npost_link = \"touch hook-ran\"\n").unwrap();
+            }
+            let hook_ran = |cache: &Cache, username: &str| cache.user(username).dotfiles_path().join("hook-ran").exists();
+
+            cache.user("jenny").link(true, false).unwrap();
+            assert!(!hook_ran(cache, "jenny"), "hooks should not run unless enabled");
+
+            cache.environment.run_hooks = true;
+            let config = symlink::Config::new(&cache.environment);
+            cache.user("jenny").relink(&config, true, false).unwrap();
+            assert!(hook_ran(cache, "jenny"));
+
+            let bob_config = symlink::Config::with_home(cache.environment.home_path.join("bob"));
+            fs::create_dir_all(&bob_config.home_path).unwrap();
+            cache.user("bob").link_ext(&bob_config, true, false).unwrap();
+            assert!(!hook_ran(cache, "bob"), "hooks should never run for other users");
+        });
+    }
+
+    #[test]
+    fn hooks_never_run_for_the_configured_user() {
+        with_cache(|cache| {
+            let mut user_cache = cache.user("bob");
+            user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
+            fs::write(user_cache.dotfiles_path().join(repository::CONFIG_FILE_NAME),
+                      "[hooks]\npost_link = \"touch hook-ran\"\n").unwrap();
+
+            // Such as with `POLK_USER=bob`.
+            cache.environment.username = "bob".to_owned();
+            cache.environment.run_hooks = true;
+            cache.user("bob").link(true, false).unwrap();
+            assert!(!cache.user("bob").dotfiles_path().join("hook-ran").exists(),
+                    "hooks should only run for the user running polk");
+        });
+    }
+
+    #[test]
+    fn untracked_nested_repositories_are_not_dotfiles() {
+        with_user_cache(|user_cache| {
+            user_cache.grab(&DOTFILES_SOURCE, false).unwrap();
+            let dotfiles_path = user_cache.dotfiles_path();
+            fs::write(dotfiles_path.join(repository::CONFIG_FILE_NAME), "include_untracked = true").unwrap();
+
+            let plugin_path = dotfiles_path.join(".vim/bundle/plugin");
+            fs::create_dir_all(plugin_path.join(".git")).unwrap();
+            fs::write(plugin_path.join("plugin.vim"), "").unwrap();
+            // Worktrees have a `.git` file rather than a directory.
+            let worktree_path = dotfiles_path.join(".vim/bundle/worktree");
+            fs::create_dir_all(&worktree_path).unwrap();
+            fs::write(worktree_path.join(".git"), "gitdir: /nonexistent").unwrap();
+            fs::write(worktree_path.join("worktree.vim"), "").unwrap();
+            fs::write(dotfiles_path.join(".vim/vimrc"), "").unwrap();
+
+            let relative_paths: Vec<_> = user_cache.dotfiles().unwrap().into_iter().map(|d| d.relative_path).collect();
+            assert!(relative_paths.contains(&PathBuf::from(".vim/vimrc")));
+            assert_eq!(relative_paths.len(), DOTFILES.len() + 1);
+        });
+    }
+}
@@ -0,0 +1,167 @@
+//! Defaults for polk, read from a configuration file.
+
+use {Environment, Error, ResultExt};
+use symlink;
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::fs;
+
+/// The name of the configuration file inside the configuration directory.
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Global defaults for polk.
+///
+/// Every setting is optional. Settings given on the command line take
+/// precedence over `POLK_*` environment variables, which take precedence
+/// over the configuration file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The user whose dotfiles are used.
+    pub user: Option<String>,
+    /// Where `grab` and `setup` get dotfiles from when no source is given.
+    pub source: Option<String>,
+    /// The directory to deploy dotfiles into, relative to the home directory.
+    pub target: Option<PathBuf>,
+    /// How files are deployed.
+    pub link_mode: Option<symlink::Mode>,
+    /// What to do about something that is in the way of a dotfile.
+    pub conflicts: Option<symlink::Conflicts>,
+    /// Features to enable on top of the ones this machine has.
+    pub features: Option<Vec<String>>,
+    /// How reports such as `polk status` are printed.
+    pub format: Option<Format>,
+    /// Whether to run the hooks of your own dotfiles.
+    pub run_hooks: Option<bool>,
+}
+
+/// How reports are printed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Text for people to read.
+    Text,
+    /// JSON for other programs to read.
+    Json,
+}
+
+impl Config {
+    /// Loads the configuration file.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let config_toml = fs::read_to_string(path).
+            chain_err(|| format!("could not read '{}'", path.display()))?;
+
+        toml::from_str(&config_toml).chain_err(|| format!("could not parse '{}'", path.display()))
+    }
+
+    /// Loads the configuration file from the configuration directory of an environment.
+    ///
+    /// Returns the default configuration if there is no configuration file.
+    pub fn load_default(environment: &Environment) -> Result<Self, Error> {
+        let path = environment.config_path.join(CONFIG_FILE_NAME);
+
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+
+        Config::load(&path)
+    }
+
+    /// Overrides settings with any `POLK_*` environment variables that are set.
+    ///
+    /// `POLK_FEATURES` is a comma separated list. A relative `POLK_TARGET` is
+    /// resolved against `working_path`, like a path given on the command line.
+    pub fn override_from_env(&mut self, environment: &Environment, working_path: &Path) -> Result<(), Error> {
+        if let Some(user) = environment.var("POLK_USER")? {
+            self.user = Some(user.to_owned());
+        }
+        if let Some(source) = environment.var("POLK_SOURCE")? {
+            self.source = Some(source.to_owned());
+        }
+        if let Some(target) = environment.var("POLK_TARGET")? {
+            self.target = Some(working_path.join(target));
+        }
+        if let Some(link_mode) = environment.var("POLK_LINK_MODE")? {
+            self.link_mode = Some(link_mode.parse().chain_err(|| "invalid POLK_LINK_MODE")?);
+        }
+        if let Some(conflicts) = environment.var("POLK_CONFLICTS")? {
+            self.conflicts = Some(conflicts.parse().chain_err(|| "invalid POLK_CONFLICTS")?);
+        }
+        if let Some(features) = environment.var("POLK_FEATURES")? {
+            self.features = Some(features.split(',').map(str::trim).filter(|f| !f.is_empty()).map(ToOwned::to_owned).collect());
+        }
+        if let Some(format) = environment.var("POLK_FORMAT")? {
+            self.format = Some(format.parse().chain_err(|| "invalid POLK_FORMAT")?);
+        }
+        if let Some(run_hooks) = environment.var("POLK_RUN_HOOKS")? {
+            self.run_hooks = Some(run_hooks.parse().chain_err(|| "invalid POLK_RUN_HOOKS")?);
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => bail!("unknown format '{}', expected 'text' or 'json'", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn settings_are_optional() {
+        assert_eq!(toml::from_str::<Config>("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn settings_are_parsed() {
+        let config: Config = toml::from_str(r#"
+            user = "jenny"
+            link_mode = "copy"
+            conflicts = "backup"
+            features = ["macos"]
+            format = "json"
+            run_hooks = true
+        "#).unwrap();
+
+        assert_eq!(config.user.as_deref(), Some("jenny"));
+        assert_eq!(config.link_mode, Some(symlink::Mode::Copy));
+        assert_eq!(config.conflicts, Some(symlink::Conflicts::Backup));
+        assert_eq!(config.features, Some(vec!["macos".to_owned()]));
+        assert_eq!(config.format, Some(Format::Json));
+        assert_eq!(config.run_hooks, Some(true));
+    }
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        assert!(toml::from_str::<Config>("colour = true").is_err());
+    }
+
+    #[test]
+    fn environment_overrides_settings() {
+        let mut environment = Environment::new("/home/jenny", "jenny");
+        environment.vars.insert("POLK_USER".to_owned(), "sam".into());
+        environment.vars.insert("POLK_SOURCE".to_owned(), "".into());
+        environment.vars.insert("POLK_FEATURES".to_owned(), "linux, work,".into());
+        environment.vars.insert("POLK_TARGET".to_owned(), "container/home".into());
+
+        let mut config = Config { source: Some("github:jenny/dotfiles".to_owned()), ..Config::default() };
+        config.override_from_env(&environment, Path::new("/srv")).unwrap();
+
+        assert_eq!(config.user.as_deref(), Some("sam"));
+        assert_eq!(config.source.as_deref(), Some("github:jenny/dotfiles"));
+        assert_eq!(config.features, Some(vec!["linux".to_owned(), "work".to_owned()]));
+        assert_eq!(config.target, Some(PathBuf::from("/srv/container/home")));
+        assert_eq!(config.link_mode, None);
+    }
+}
@@ -0,0 +1,135 @@
+use Error;
+
+use similar::TextDiff;
+use walkdir::WalkDir;
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::{fs, io, str};
+
+/// The difference between two versions of a file.
+///
+/// This is usually a file in the home directory and what polk would put there.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    /// The path of the old version.
+    pub old_path: PathBuf,
+    /// The path of the new version.
+    pub new_path: PathBuf,
+    /// The old contents, if the file exists.
+    pub old: Option<Vec<u8>>,
+    /// The new contents, if the file exists.
+    pub new: Option<Vec<u8>>,
+    /// Whether the contents should never be shown, such as for decrypted secrets.
+    pub hidden: bool,
+}
+
+impl Change {
+    /// Checks if the contents actually differ.
+    pub fn is_change(&self) -> bool {
+        self.old != self.new
+    }
+
+    /// Formats the change as a unified diff.
+    pub fn unified(&self) -> String {
+        let old_header = self.old_path.display().to_string();
+        let new_header = self.new_path.display().to_string();
+
+        if self.hidden {
+            return format!("Secret files {} and {} differ\n", old_header, new_header);
+        }
+
+        let old = self.old.as_ref().map(|o| str::from_utf8(o));
+        let new = self.new.as_ref().map(|n| str::from_utf8(n));
+
+        match (old.unwrap_or(Ok("")), new.unwrap_or(Ok(""))) {
+            (Ok(old_text), Ok(new_text)) => {
+                let old_header = if self.old.is_some() { old_header } else { "/dev/null".to_owned() };
+                let new_header = if self.new.is_some() { new_header } else { "/dev/null".to_owned() };
+
+                TextDiff::from_lines(old_text, new_text).unified_diff().
+                    header(&old_header, &new_header).
+                    to_string()
+            },
+            _ => format!("Binary files {} and {} differ\n", old_header, new_header),
+        }
+    }
+}
+
+/// Reads a file, following symlinks.
+///
+/// Returns `None` if there is no file.
+pub fn read(path: &Path) -> Result<Option<Vec<u8>>, Error> {
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound || e.kind() == io::ErrorKind::NotADirectory => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Compares every file inside a directory in the home directory with a directory in the repository.
+pub fn directories(destination: &Path, source: &Path) -> Result<Vec<Change>, Error> {
+    let mut relative_paths = BTreeSet::new();
+
+    for root in [destination, source].iter() {
+        for entry in WalkDir::new(root).min_depth(1) {
+            let entry = entry?;
+
+            if entry.file_type().is_file() {
+                relative_paths.insert(entry.path().strip_prefix(root).unwrap().to_owned());
+            }
+        }
+    }
+
+    let mut changes = Vec::new();
+
+    for relative_path in relative_paths {
+        let change = Change {
+            old_path: destination.join(&relative_path),
+            new_path: source.join(&relative_path),
+            old: self::read(&destination.join(&relative_path))?,
+            new: self::read(&source.join(&relative_path))?,
+            hidden: false,
+        };
+
+        if change.is_change() {
+            changes.push(change);
+        }
+    }
+
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn change(old: Option<&str>, new: Option<&str>) -> Change {
+        Change {
+            old_path: PathBuf::from("/home/jenny/.bashrc"),
+            new_path: PathBuf::from("/dotfiles/.bashrc"),
+            old: old.map(|o| o.as_bytes().to_owned()),
+            new: new.map(|n| n.as_bytes().to_owned()),
+            hidden: false,
+        }
+    }
+
+    #[test]
+    fn changes_are_unified_diffs() {
+        assert_eq!(change(Some("a\nb\n"), Some("a\nc\n")).unified(),
+                   "--- /home/jenny/.bashrc\n+++ /dotfiles/.bashrc\n@@ -1,2 +1,2 @@\n a\n-b\n+c\n");
+        assert_eq!(change(None, Some("a\n")).unified(),
+                   "--- /dev/null\n+++ /dotfiles/.bashrc\n@@ -0,0 +1 @@\n+a\n");
+    }
+
+    #[test]
+    fn hidden_and_binary_contents_are_not_shown() {
+        let mut secret = change(Some("password"), Some("hunter2"));
+        secret.hidden = true;
+        assert!(!secret.unified().contains("hunter2"));
+
+        let mut binary = change(Some("a"), None);
+        binary.new = Some(vec![0xff, 0xfe]);
+        assert!(binary.unified().starts_with("Binary files"));
+    }
+}
@@ -0,0 +1,314 @@
+//! Checking the cache, manifests and links for problems.
+
+use {Cache, UserCache, Error, ResultExt};
+use {backend, journal, ledger};
+use manifest::UserManifest;
+use journal::Journal;
+use ledger::Ledger;
+
+use sym;
+
+use std::path::PathBuf;
+use std::fs;
+
+/// Something wrong with polk's state.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub description: String,
+    /// How to fix the problem, if it is safe to do so automatically.
+    pub fix: Option<Fix>,
+}
+
+/// A safe repair for a problem.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fix {
+    /// Move a leftover backup back into place.
+    RestoreBackup { backup: PathBuf, original: PathBuf },
+    /// Delete a leftover backup whose original has since been recreated.
+    RemoveBackup(PathBuf),
+    /// Delete a link that points to something which no longer exists, and forget it.
+    RemoveLink(PathBuf),
+    /// Forget a link that is no longer there.
+    ForgetLink(PathBuf),
+    /// Recreate the shortcut symlink to a dotfiles repository.
+    CreateShortcut { path: PathBuf, target: PathBuf },
+}
+
+impl Problem {
+    fn new<S>(description: S) -> Self where S: Into<String> {
+        Problem { description: description.into(), fix: None }
+    }
+
+    fn fixable<S>(description: S, fix: Fix) -> Self where S: Into<String> {
+        Problem { description: description.into(), fix: Some(fix) }
+    }
+}
+
+/// Looks for problems with the cache and everything polk has created.
+pub fn diagnose(cache: &Cache) -> Result<Vec<Problem>, Error> {
+    let mut problems = Vec::new();
+
+    if cache.users_path().exists() && !cache.users_path().is_dir() {
+        problems.push(Problem::new(format!("'{}' is not a directory", cache.users_path().display())));
+        return Ok(problems);
+    }
+
+    if cache.users_path().is_dir() {
+        for entry in fs::read_dir(cache.users_path())? {
+            let path = entry?.path();
+
+            if !path.is_dir() {
+                problems.push(Problem::new(format!("unexpected file '{}' in the cache", path.display())));
+            }
+        }
+    }
+
+    for user_cache in cache.user_caches()? {
+        self::diagnose_user(&user_cache, &mut problems)?;
+    }
+
+    // Links into anything a backup will restore are not really dangling.
+    let restorable: Vec<PathBuf> = problems.iter().filter_map(|problem| match problem.fix {
+        Some(Fix::RestoreBackup { ref original, .. }) => Some(original.clone()),
+        _ => None,
+    }).collect();
+
+    match cache.ledger() {
+        Ok(ledger) => self::diagnose_ledger(cache, &ledger, &restorable, &mut problems)?,
+        Err(e) => problems.push(Problem::new(format!("the link ledger is unreadable: {}", e))),
+    }
+
+    Ok(problems)
+}
+
+/// Looks for problems with a single user's cache.
+fn diagnose_user(user_cache: &UserCache, problems: &mut Vec<Problem>) -> Result<(), Error> {
+    let username = &user_cache.username;
+
+    for (backup, original) in journal::backups(&user_cache.base_path())? {
+        if original.exists() {
+            problems.push(Problem::fixable(format!("leftover backup '{}' for user '{}'", backup.display(), username),
+                                           Fix::RemoveBackup(backup)));
+        } else {
+            problems.push(Problem::fixable(format!("'{}' was moved aside to '{}' and never restored",
+                                                   original.display(), backup.display()),
+                                           Fix::RestoreBackup { backup, original }));
+        }
+    }
+
+    if !user_cache.manifest_path().exists() {
+        problems.push(Problem::new(format!("user '{}' has no manifest, grab their dotfiles again", username)));
+        return Ok(());
+    }
+
+    let manifest = match UserManifest::load(&user_cache.manifest_path()) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            problems.push(Problem::new(format!("the manifest for user '{}' is unreadable: {}", username, e)));
+            return Ok(());
+        },
+    };
+
+    if !user_cache.dotfiles_path().is_dir() {
+        // A restorable backup has already been reported.
+        if journal::backups(&user_cache.base_path())?.is_empty() {
+            problems.push(Problem::new(format!("the dotfiles for user '{}' are missing, grab them again", username)));
+        }
+        return Ok(());
+    }
+
+    match backend::open(&user_cache.dotfiles_path(), manifest.source) {
+        Ok(backend) => {
+            for problem in backend.diagnose()? {
+                problems.push(Problem::new(format!("the dotfiles for user '{}': {}", username, problem)));
+            }
+        },
+        Err(e) => {
+            problems.push(Problem::new(format!("the dotfiles for user '{}' cannot be opened: {}", username, e)));
+        },
+    }
+
+    if let Err(e) = user_cache.repository_config().and_then(|config| config.mapper(user_cache.environment())) {
+        problems.push(Problem::new(format!("the dotfiles for user '{}' are misconfigured: {}", username, e)));
+    }
+
+    Ok(())
+}
+
+/// Looks for links which are no longer where polk put them.
+fn diagnose_ledger(cache: &Cache,
+                   ledger: &Ledger,
+                   restorable: &[PathBuf],
+                   problems: &mut Vec<Problem>) -> Result<(), Error> {
+    for entry in ledger.links.iter() {
+        if restorable.iter().any(|original| entry.target.starts_with(original)) {
+            continue;
+        }
+
+        let metadata = fs::symlink_metadata(&entry.path);
+
+        if metadata.is_err() {
+            let is_shortcut = entry.target == cache.user(entry.user.clone()).dotfiles_path();
+
+            if is_shortcut && entry.target.is_dir() {
+                problems.push(Problem::fixable(format!("the shortcut '{}' to the dotfiles of user '{}' is missing",
+                                                       entry.path.display(), entry.user),
+                                               Fix::CreateShortcut { path: entry.path.clone(), target: entry.target.clone() }));
+            } else {
+                problems.push(Problem::fixable(format!("'{}' has been removed by something other than polk",
+                                                       entry.path.display()),
+                                               Fix::ForgetLink(entry.path.clone())));
+            }
+        } else if entry.mode == ledger::Mode::Symlink && entry.is_intact() && !entry.target.exists() {
+            problems.push(Problem::fixable(format!("'{}' points to '{}', which no longer exists",
+                                                   entry.path.display(), entry.target.display()),
+                                           Fix::RemoveLink(entry.path.clone())));
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies the fixes for some problems.
+///
+/// Either every fix is applied, or none are.
+pub fn fix(cache: &Cache, problems: &[Problem], wait: bool, verbose: bool) -> Result<(), Error> {
+    cache.change_ledger(wait, verbose, |ledger, journal| {
+        for fix in problems.iter().filter_map(|p| p.fix.as_ref()) {
+            ilog!("{}", fix.description());
+            self::apply(fix, ledger, journal, verbose).chain_err(|| format!("could not {}", fix.description()))?;
+        }
+
+        Ok(())
+    })
+}
+
+/// Applies a single fix.
+fn apply(fix: &Fix, ledger: &mut Ledger, journal: &mut Journal, verbose: bool) -> Result<(), Error> {
+    match *fix {
+        Fix::RestoreBackup { ref backup, ref original } => {
+            journal.rename(backup, original)?;
+        },
+        Fix::RemoveBackup(ref backup) => {
+            // Moved paths are only deleted once everything else has been fixed.
+            journal.move_aside(backup)?;
+        },
+        Fix::RemoveLink(ref path) => {
+            ledger.remove_links(journal, verbose, |entry| entry.path == *path)?;
+        },
+        Fix::ForgetLink(ref path) => {
+            ledger.links.retain(|entry| entry.path != *path);
+        },
+        Fix::CreateShortcut { ref path, ref target } => {
+            journal.track(path)?;
+            sym::symlink_dir(target, path)?;
+        },
+    }
+
+    Ok(())
+}
+
+impl Fix {
+    /// Describes what the fix does.
+    pub fn description(&self) -> String {
+        match *self {
+            Fix::RestoreBackup { ref backup, ref original } => {
+                format!("restore '{}' to '{}'", backup.display(), original.display())
+            },
+            Fix::RemoveBackup(ref backup) => format!("delete '{}'", backup.display()),
+            Fix::RemoveLink(ref path) => format!("delete '{}'", path.display()),
+            Fix::ForgetLink(ref path) => format!("forget '{}'", path.display()),
+            Fix::CreateShortcut { ref path, ref target } => {
+                format!("link '{}' to '{}'", path.display(), target.display())
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testing::with_cache;
+
+    use std::os::unix;
+
+    fn link(path: PathBuf, target: PathBuf) -> ledger::Entry {
+        ledger::Entry {
+            path,
+            target,
+            user: "jenny".to_owned(),
+            home: PathBuf::from("/home/jenny"),
+            revision: None,
+            mode: ledger::Mode::Symlink,
+            checksum: None,
+        }
+    }
+
+    fn has_problem(problems: &[Problem], description: &str) -> bool {
+        problems.iter().any(|problem| problem.description.contains(description))
+    }
+
+    #[test]
+    fn broken_caches_are_reported() {
+        with_cache(|cache| {
+            fs::create_dir_all(cache.user("jenny").base_path()).unwrap();
+            fs::write(cache.users_path().join("stray"), "").unwrap();
+            fs::write(cache.ledger_path(), "not toml [").unwrap();
+
+            let problems = diagnose(cache).unwrap();
+            assert!(has_problem(&problems, "unexpected file"));
+            assert!(has_problem(&problems, "user 'jenny' has no manifest"));
+            assert!(has_problem(&problems, "the link ledger is unreadable"));
+            assert!(problems.iter().all(|problem| problem.fix.is_none()), "none of these are safe to fix");
+        });
+    }
+
+    #[test]
+    fn leftover_backups_are_restored_or_removed() {
+        with_cache(|cache| {
+            let user_cache = cache.user("jenny");
+            fs::create_dir_all(user_cache.base_path().join(".dotfiles.123.backup")).unwrap();
+            fs::write(user_cache.base_path().join(".manifest.toml.456.backup"), "").unwrap();
+            fs::write(user_cache.manifest_path(), "").unwrap();
+
+            let problems = diagnose(cache).unwrap();
+            assert!(problems.iter().any(|problem| match problem.fix {
+                Some(Fix::RestoreBackup { ref original, .. }) => *original == user_cache.dotfiles_path(),
+                _ => false,
+            }));
+            assert!(problems.iter().any(|problem| problem.fix == Some(Fix::RemoveBackup(user_cache.base_path().join(".manifest.toml.456.backup")))));
+
+            fix(cache, &problems, true, false).unwrap();
+            assert!(user_cache.dotfiles_path().is_dir());
+            assert!(journal::backups(&user_cache.base_path()).unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn broken_links_are_fixed() {
+        with_cache(|cache| {
+            let home_path = cache.environment.home_path.clone();
+            let dotfiles_path = cache.user("jenny").dotfiles_path();
+            fs::create_dir_all(&dotfiles_path).unwrap();
+            unix::fs::symlink("/nonexistent", home_path.join(".vimrc")).unwrap();
+
+            cache.change_ledger(true, false, |ledger, _| {
+                ledger.record(link(home_path.join(".vimrc"), PathBuf::from("/nonexistent")));
+                ledger.record(link(home_path.join(".bashrc"), dotfiles_path.join(".bashrc")));
+                ledger.record(link(home_path.join(".dot"), dotfiles_path.clone()));
+                Ok(())
+            }).unwrap();
+
+            let problems = diagnose(cache).unwrap();
+            let fixes: Vec<_> = problems.iter().filter_map(|problem| problem.fix.clone()).collect();
+            assert!(fixes.contains(&Fix::RemoveLink(home_path.join(".vimrc"))));
+            assert!(fixes.contains(&Fix::ForgetLink(home_path.join(".bashrc"))));
+            assert!(fixes.contains(&Fix::CreateShortcut { path: home_path.join(".dot"), target: dotfiles_path.clone() }));
+
+            fix(cache, &problems, true, false).unwrap();
+            assert!(fs::symlink_metadata(home_path.join(".vimrc")).is_err());
+            assert_eq!(fs::read_link(home_path.join(".dot")).unwrap(), dotfiles_path);
+            assert!(diagnose(cache).unwrap().iter().all(|problem| problem.fix.is_none()));
+        });
+    }
+}
@@ -0,0 +1,124 @@
+//! The home directory, user and cache that polk works with.
+
+use {Error, ResultExt};
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::path::PathBuf;
+use std::env;
+
+/// The parts of the outside world that polk depends on.
+///
+/// Nothing else reads the home directory, the current user or environment
+/// variables, so several environments can be used side by side, such as in tests.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Environment {
+    /// The home directory that dotfiles are linked into by default.
+    pub home_path: PathBuf,
+    /// The user whose dotfiles are used by default.
+    pub username: String,
+    /// The user actually running polk.
+    ///
+    /// Unlike `username`, this is never overridden by configuration.
+    pub real_username: String,
+    /// The directory that holds polk's cache.
+    pub cache_path: PathBuf,
+    /// The directory that holds polk's configuration.
+    pub config_path: PathBuf,
+    /// The shell to run for `polk shell`.
+    pub shell_path: String,
+    /// Features to enable on top of the ones the machine has.
+    pub features: Vec<String>,
+    /// Whether the hooks of the real user's dotfiles may be run.
+    ///
+    /// Hooks run arbitrary commands, so they are never run unless asked
+    /// for, and never for the dotfiles of anybody else.
+    pub run_hooks: bool,
+    /// Environment variables, such as the XDG base directories, `POLK_*`
+    /// overrides and anything templates refer to.
+    pub vars: BTreeMap<String, OsString>,
+}
+
+impl Environment {
+    /// Creates an environment with the default XDG base directories inside the home directory.
+    pub fn new<P, S>(home_path: P, username: S) -> Self
+        where P: Into<PathBuf>, S: Into<String> {
+        let home_path = home_path.into();
+        let username = username.into();
+
+        Environment {
+            cache_path: home_path.join(".local/share/polk"),
+            config_path: home_path.join(".config/polk"),
+            home_path,
+            real_username: username.clone(),
+            username,
+            shell_path: "/bin/sh".to_owned(),
+            features: Vec::new(),
+            run_hooks: false,
+            vars: BTreeMap::new(),
+        }
+    }
+
+    /// Gets the environment of the current process.
+    ///
+    /// The cache lives in `$POLK_HOME` if it is set, or `$XDG_DATA_HOME/polk`
+    /// otherwise. The configuration lives in `$XDG_CONFIG_HOME/polk`.
+    ///
+    /// A relative `$POLK_HOME` is kept as it is, for the caller to resolve
+    /// against whichever directory it was given in.
+    pub fn current() -> Result<Self, Error> {
+        let home_path = env::home_dir().ok_or("could not find your home directory")?;
+        let username = env::var("USER").chain_err(|| "could not get username")?;
+
+        let mut environment = Environment::new(home_path, username);
+        // Nothing can refer to variables whose names are not valid UTF-8.
+        environment.vars = env::vars_os().filter_map(|(name, value)| Some((name.into_string().ok()?, value))).collect();
+
+        environment.config_path = environment.xdg_directory("XDG_CONFIG_HOME", ".config").join("polk");
+        environment.cache_path = match environment.var_os("POLK_HOME") {
+            Some(path) => PathBuf::from(path),
+            None => environment.default_cache_path(),
+        };
+
+        if let Some(shell_path) = environment.var("SHELL")? {
+            environment.shell_path = shell_path.to_owned();
+        }
+
+        Ok(environment)
+    }
+
+    /// Gets an environment variable, treating an empty one as unset.
+    pub fn var_os(&self, name: &str) -> Option<&OsStr> {
+        self.vars.get(name).map(OsString::as_os_str).filter(|value| !value.is_empty())
+    }
+
+    /// Gets an environment variable that must be valid UTF-8, treating an empty one as unset.
+    pub fn var(&self, name: &str) -> Result<Option<&str>, Error> {
+        match self.var_os(name) {
+            Some(value) => match value.to_str() {
+                Some(value) => Ok(Some(value)),
+                None => bail!("{} is not valid UTF-8", name),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Gets an XDG base directory, falling back to its default inside the home directory.
+    pub fn xdg_directory(&self, variable: &str, default: &str) -> PathBuf {
+        match self.var_os(variable).map(PathBuf::from) {
+            // The specification says relative paths should be ignored.
+            Some(ref path) if path.is_absolute() => path.clone(),
+            _ => self.home_path.join(default),
+        }
+    }
+
+    /// Where the cache lives unless `$POLK_HOME` or `--cache-dir` say otherwise.
+    pub fn default_cache_path(&self) -> PathBuf {
+        self.xdg_directory("XDG_DATA_HOME", ".local/share").join("polk")
+    }
+
+    /// Where the cache was kept before polk followed the XDG base directories.
+    pub fn legacy_cache_path(&self) -> PathBuf {
+        self.home_path.join(".polk")
+    }
+}
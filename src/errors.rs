@@ -1,3 +1,6 @@
+use std::error::Error as StdError;
+use std::path::PathBuf;
+
 error_chain! {
     types {
         Error, ErrorKind, ResultExt, Result;
@@ -10,4 +13,77 @@ error_chain! {
         Term(::term::Error);
         Git(::git2::Error);
     }
+
+    errors {
+        /// There are no dotfiles grabbed for a user.
+        NotGrabbed(username: String) {
+            description("no dotfiles have been grabbed")
+            display("there are no dotfiles grabbed for user '{}'", username)
+        }
+
+        /// The dotfiles repository has uncommitted changes.
+        DirtyWorktree(path: PathBuf) {
+            description("dotfiles repository has uncommitted changes")
+            display("dotfiles repository needs to have a clean worktree ({})", path.display())
+        }
+
+        /// The dotfiles repository has nowhere to update from.
+        NoRemote(path: PathBuf) {
+            description("dotfiles repository has no remotes")
+            display("dotfiles repository has no remotes set up ({})", path.display())
+        }
+
+        /// A user manifest could not be parsed.
+        ManifestParse(path: PathBuf) {
+            description("could not parse user manifest")
+            display("could not parse user manifest '{}'", path.display())
+        }
+
+        /// The dotfiles repository has something other than a branch checked out.
+        UnsupportedHead(head: String) {
+            description("HEAD is not a branch")
+            display("{} is checked out, but dotfiles can only be updated on a branch", head)
+        }
+
+        /// Something polk did not create is in the way of a link.
+        LinkConflict(path: PathBuf, existing: &'static str) {
+            description("something is in the way of a link")
+            display("there is an existing {} at '{}'", existing, path.display())
+        }
+
+        /// Another polk process holds a lock that is needed.
+        Locked(path: PathBuf) {
+            description("locked by another polk process")
+            display("'{}' is locked by another polk process", path.display())
+        }
+
+        /// A feature that is not in the global feature set.
+        UnknownFeature(feature: String) {
+            description("unknown feature")
+            display("unknown feature '{}'", feature)
+        }
+    }
+}
+
+impl Error {
+    /// The kind of the outermost error in the chain that says what went wrong.
+    ///
+    /// Errors are often given extra context with `chain_err`, which hides
+    /// their kind behind a `Msg`. This finds the kind again.
+    pub fn specific_kind(&self) -> &ErrorKind {
+        let mut cause: Option<&(dyn StdError + 'static)> = Some(self);
+
+        while let Some(error) = cause {
+            if let Some(error) = error.downcast_ref::<Error>() {
+                match *error.kind() {
+                    ErrorKind::Msg(..) => (),
+                    ref kind => return kind,
+                }
+            }
+
+            cause = error.source();
+        }
+
+        self.kind()
+    }
 }
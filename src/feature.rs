@@ -1,4 +1,4 @@
-use Dotfile;
+use {Dotfile, Environment, Error, ErrorKind};
 
 use std::collections::HashSet;
 use std::env::consts;
@@ -6,7 +6,7 @@ use std::env::consts;
 /// All architecture names.
 ///
 /// Taken from the documentation of the `std::env::consts::OS` constant.
-pub const OS_NAMES: &'static [&'static str] = &[
+pub const OS_NAMES: &[&str] = &[
     "linux",
     "macos",
     "ios",
@@ -23,7 +23,7 @@ pub const OS_NAMES: &'static [&'static str] = &[
 /// All family names.
 ///
 /// Taken from the documentation of the `std::env::consts::FAMILY` constant.
-pub const FAMILIES: &'static [&'static str] = &[
+pub const FAMILIES: &[&str] = &[
     "unix",
     "windows",
 ];
@@ -31,7 +31,7 @@ pub const FAMILIES: &'static [&'static str] = &[
 /// All architecture names.
 ///
 /// Taken from the documentation of the `std::env::consts::ARCH` constant.
-pub const ARCH_NAMES: &'static [&'static str] = &[
+pub const ARCH_NAMES: &[&str] = &[
     "x86",
     "x86_64",
     "arm",
@@ -45,7 +45,7 @@ pub const ARCH_NAMES: &'static [&'static str] = &[
 ];
 
 /// All features defined across all platforms.
-pub const ALL_FEATURES: &'static [&'static [&'static str]] = &[
+pub const ALL_FEATURES: &[&[&str]] = &[
     OS_NAMES, FAMILIES, ARCH_NAMES,
 ];
 
@@ -57,7 +57,9 @@ pub struct FeatureSet {
 
 impl FeatureSet {
     /// Gets the feature set for the current system.
-    pub fn current_system() -> Self {
+    ///
+    /// Fails if the system has a feature polk does not know about, such as a new OS.
+    pub fn current_system() -> Result<Self, Error> {
         let mut enabled_features = HashSet::new();
 
         enabled_features.insert(consts::OS);
@@ -67,13 +69,26 @@ impl FeatureSet {
         FeatureSet::new(enabled_features)
     }
 
+    /// Gets the feature set for the current system, plus the extra features of an environment.
+    pub fn for_environment(environment: &Environment) -> Result<Self, Error> {
+        let mut feature_set = FeatureSet::current_system()?;
+
+        for name in environment.features.iter() {
+            let feature = ALL_FEATURES.iter().flat_map(|fs| fs.iter()).find(|&f| f == name).
+                ok_or_else(|| ErrorKind::UnknownFeature(name.clone()))?;
+            feature_set.enabled_features.insert(feature);
+        }
+
+        Ok(feature_set)
+    }
+
     /// Creates a new feature set.
-    pub fn new(enabled_features: HashSet<&'static str>) -> Self {
+    pub fn new(enabled_features: HashSet<&'static str>) -> Result<Self, Error> {
         for feature in enabled_features.iter() {
-            validate_feature(feature);
+            validate_feature(feature)?;
         }
 
-        FeatureSet { enabled_features: enabled_features }
+        Ok(FeatureSet { enabled_features })
     }
 
     /// Checks if a dotfile is supported.
@@ -86,15 +101,23 @@ impl FeatureSet {
     /// the feature names.
     ///
     /// For example, `.tmux.linux.conf` would get resolved to `.tmux.os.conf`.
-    pub fn substitute_enabled_feature_names(&self, dotfile: &mut Dotfile) {
-        let mut file_name = dotfile.relative_path.file_name().unwrap().to_str().unwrap().to_owned();
+    pub fn substitute_enabled_feature_names(&self, dotfile: &mut Dotfile) -> Result<(), Error> {
+        let mut file_name = match dotfile.relative_path.file_name().map(|name| name.to_str()) {
+            Some(Some(file_name)) => file_name.to_owned(),
+            // Feature names are always UTF-8, so there is nothing to substitute.
+            Some(None) if self::required_features(dotfile).is_empty() => return Ok(()),
+            Some(None) => bail!("'{}' has feature flags in its name, but the name is not valid UTF-8",
+                                dotfile.relative_path.display()),
+            None => return Ok(()),
+        };
 
         for feature_value in self.enabled_features.iter() {
-            let feature_name = self::feature_name(feature_value);
+            let feature_name = self::feature_name(feature_value)?;
             file_name = file_name.replace(feature_value, feature_name);
         }
 
         dotfile.relative_path = dotfile.relative_path.with_file_name(file_name);
+        Ok(())
     }
 
     /// Gets a list of all disabled features.
@@ -107,7 +130,10 @@ impl FeatureSet {
 
 /// Builds a list of all required features for a dotfile.
 pub fn required_features(dotfile: &Dotfile) -> HashSet<&'static str> {
-    let file_name: String = dotfile.relative_path.file_name().unwrap().to_str().unwrap().to_owned();
+    let file_name = match dotfile.relative_path.file_name() {
+        Some(file_name) => file_name.to_string_lossy(),
+        None => return HashSet::new(),
+    };
 
     file_name.split('.').filter_map(|part| {
         for feature_set in ALL_FEATURES {
@@ -122,34 +148,45 @@ pub fn required_features(dotfile: &Dotfile) -> HashSet<&'static str> {
 
 /// Gets the name of a feature given its value
 /// For example, `.tmux.linux.conf` -> `.tmux.os.conf`.
-fn feature_name(value: &str) -> &'static str {
-    if OS_NAMES.iter().any(|&o| o == value) { return "os" };
-    if FAMILIES.iter().any(|&o| o == value) { return "family" };
-    if ARCH_NAMES.iter().any(|&o| o == value) { return "arch" };
+fn feature_name(value: &str) -> Result<&'static str, Error> {
+    match feature_kind(value) {
+        Some(kind) => Ok(kind),
+        None => bail!(ErrorKind::UnknownFeature(value.to_owned())),
+    }
+}
+
+/// Gets the kind of a feature given its value, if it is known.
+///
+/// For example, `linux` is an `os`.
+pub fn feature_kind(value: &str) -> Option<&'static str> {
+    if OS_NAMES.contains(&value) { return Some("os") };
+    if FAMILIES.contains(&value) { return Some("family") };
+    if ARCH_NAMES.contains(&value) { return Some("arch") };
 
-    panic!("unknown feature: '{}'", value);
+    None
 }
 
-/// Panics if a feature name isn't known to this module.
-fn validate_feature(feature: &'static str) {
+/// Fails if a feature name isn't known to this module.
+fn validate_feature(feature: &'static str) -> Result<(), Error> {
     for feature_set in ALL_FEATURES.iter() {
-        if let Some(..) = feature_set.iter().find(|&f| f == &feature) {
-            return;
+        if feature_set.contains(&feature) {
+            return Ok(());
         }
     }
 
-    panic!("feature '{}' does not exist in the global feature set", feature);
+    bail!(ErrorKind::UnknownFeature(feature.to_owned()))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use DotfileKind;
     use std::path::Path;
 
-    static ENABLED_FEATURES: &'static [&'static str] = &["linux", "unix", "x86"];
+    static ENABLED_FEATURES: &[&str] = &["linux", "unix", "x86"];
 
     fn feature_set(features: &'static [&'static str]) -> FeatureSet {
-        FeatureSet::new(features.into_iter().cloned().collect())
+        FeatureSet::new(features.iter().cloned().collect()).unwrap()
     }
 
     fn substitute(relative_path: &'static str) -> String {
@@ -158,8 +195,9 @@ mod test {
         let mut dotfile = Dotfile {
             full_path: Path::new("unused").to_owned(),
             relative_path: Path::new(relative_path).to_owned(),
+            kind: DotfileKind::File,
         };
-        feature_set.substitute_enabled_feature_names(&mut dotfile);
+        feature_set.substitute_enabled_feature_names(&mut dotfile).unwrap();
 
         dotfile.relative_path.to_str().unwrap().to_owned()
     }
@@ -171,5 +209,41 @@ mod test {
         assert_eq!(substitute(".tmux.linux.x86.conf"), ".tmux.os.arch.conf");
         assert_eq!(substitute(".tmux.linux.unix.x86.conf"), ".tmux.os.family.arch.conf");
     }
+
+    #[test]
+    fn names_that_are_not_utf8_do_not_panic() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let feature_set = feature_set(ENABLED_FEATURES);
+        let mut dotfile = Dotfile {
+            full_path: Path::new("unused").to_owned(),
+            relative_path: Path::new(OsStr::from_bytes(b".vim\xff")).to_owned(),
+            kind: DotfileKind::File,
+        };
+
+        assert!(feature_set.supports(&dotfile));
+        feature_set.substitute_enabled_feature_names(&mut dotfile).unwrap();
+        assert_eq!(dotfile.relative_path, Path::new(OsStr::from_bytes(b".vim\xff")));
+
+        dotfile.relative_path = Path::new(OsStr::from_bytes(b".vim\xff.linux")).to_owned();
+        assert!(feature_set.substitute_enabled_feature_names(&mut dotfile).is_err());
+    }
+
+    #[test]
+    fn environments_can_enable_extra_features() {
+        let mut environment = Environment::new("/home/jenny", "jenny");
+        environment.features = vec!["macos".to_owned()];
+
+        let feature_set = FeatureSet::for_environment(&environment).unwrap();
+        assert!(feature_set.enabled_features.contains("macos"));
+        assert!(feature_set.enabled_features.contains(consts::OS));
+
+        environment.features = vec!["amiga".to_owned()];
+        match FeatureSet::for_environment(&environment) {
+            Err(Error(ErrorKind::UnknownFeature(name), _)) => assert_eq!(name, "amiga"),
+            result => panic!("expected an unknown feature error, got {:?}", result.map(|_| ())),
+        }
+    }
 }
 
@@ -0,0 +1,238 @@
+//! Reclaiming space used by the cache.
+
+use {Cache, UserCache, Error, ResultExt};
+use {backend, journal};
+
+use walkdir::WalkDir;
+
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use std::fs;
+
+/// Deletes leftovers from the cache and compacts the dotfiles repositories.
+///
+/// Backups are only deleted once they are older than `max_backup_age`, so
+/// that an operation which is still running never loses its backup. Caches
+/// without a manifest lose their dotfiles, but identities and template
+/// variables are never deleted as they cannot be recreated.
+///
+/// Returns the number of bytes reclaimed.
+pub fn collect(cache: &Cache, max_backup_age: Duration, verbose: bool) -> Result<u64, Error> {
+    let ledger = cache.ledger()?;
+    let mut reclaimed = 0;
+
+    for user_cache in cache.user_caches()? {
+        reclaimed += self::remove_backups(&user_cache, max_backup_age, verbose)?;
+
+        if !user_cache.manifest_path().exists() {
+            if ledger.links.iter().any(|entry| entry.user == user_cache.username) {
+                warn!("not deleting the cache for user '{}' as it still has links, unlink them first",
+                      user_cache.username);
+                continue;
+            }
+
+            let dotfiles_path = user_cache.dotfiles_path();
+            if fs::symlink_metadata(&dotfiles_path).is_ok() {
+                vlog!(verbose => "deleting the dotfiles for user '{}' as they have no manifest", user_cache.username);
+                reclaimed += self::remove(&dotfiles_path)?;
+            }
+
+            // The identity is the only copy of the key for the user's secrets.
+            let kept: Vec<_> = vec![user_cache.identity_path(), user_cache.vars_path()].into_iter()
+                .filter(|path| path.exists()).collect();
+
+            if !kept.is_empty() {
+                warn!("not deleting the cache for user '{}' as it still has {}",
+                      user_cache.username,
+                      kept.iter().map(|path| format!("'{}'", path.display())).collect::<Vec<_>>().join(" and "));
+                continue;
+            }
+
+            vlog!(verbose => "deleting the cache for user '{}' as it has no manifest", user_cache.username);
+            reclaimed += self::remove(&user_cache.base_path())?;
+            continue;
+        }
+
+        reclaimed += self::compact(&user_cache, verbose)?;
+    }
+
+    Ok(reclaimed)
+}
+
+/// Deletes the stale backups inside a user cache.
+fn remove_backups(user_cache: &UserCache, max_backup_age: Duration, verbose: bool) -> Result<u64, Error> {
+    let mut reclaimed = 0;
+
+    for (backup, original) in journal::backups(&user_cache.base_path())? {
+        let modified = fs::symlink_metadata(&backup)?.modified()?;
+        let age = SystemTime::now().duration_since(modified).unwrap_or_default();
+
+        if age < max_backup_age {
+            vlog!(verbose => "keeping '{}' as it is too recent", backup.display());
+        } else if !original.exists() {
+            warn!("not deleting '{}' as it was never restored, run `polk doctor --fix` to restore it",
+                  backup.display());
+        } else {
+            vlog!(verbose => "deleting '{}'", backup.display());
+            reclaimed += self::remove(&backup)?;
+        }
+    }
+
+    Ok(reclaimed)
+}
+
+/// Compacts a user's dotfiles repository.
+fn compact(user_cache: &UserCache, verbose: bool) -> Result<u64, Error> {
+    let dotfiles_path = user_cache.dotfiles_path();
+
+    if !dotfiles_path.is_dir() {
+        return Ok(0);
+    }
+
+    let before = self::disk_usage(&dotfiles_path)?;
+
+    let result = user_cache.manifest().and_then(|manifest| {
+        vlog!(verbose => "compacting the dotfiles for user '{}'", user_cache.username);
+        backend::open(&dotfiles_path, manifest.source)?.compact(verbose)
+    });
+
+    // One broken repository shouldn't stop the others from being compacted.
+    if let Err(e) = result {
+        warn!("could not compact the dotfiles for user '{}': {}", user_cache.username, e);
+    }
+
+    Ok(before.saturating_sub(self::disk_usage(&dotfiles_path)?))
+}
+
+/// Deletes a file or directory, returning how much space it used.
+fn remove(path: &Path) -> Result<u64, Error> {
+    let size = self::disk_usage(path)?;
+
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }.chain_err(|| format!("could not delete '{}'", path.display()))?;
+
+    Ok(size)
+}
+
+/// Adds up the sizes of everything inside a path, without following symlinks.
+fn disk_usage(path: &Path) -> Result<u64, Error> {
+    let mut size = 0;
+
+    for entry in WalkDir::new(path) {
+        size += entry?.metadata()?.len();
+    }
+
+    Ok(size)
+}
+
+/// Formats a number of bytes for people to read, such as `1.5 MiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ledger;
+    use testing::with_cache;
+
+    use std::path::PathBuf;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// Creates a backup file that was last modified some time ago.
+    fn backup(path: PathBuf, age: Duration) -> PathBuf {
+        fs::write(&path, "backup").unwrap();
+        fs::File::options().write(true).open(&path).unwrap().set_modified(SystemTime::now() - age).unwrap();
+        path
+    }
+
+    #[test]
+    fn only_old_backups_are_removed() {
+        with_cache(|cache| {
+            let user_cache = cache.user("jenny");
+            fs::create_dir_all(user_cache.base_path()).unwrap();
+            fs::write(user_cache.manifest_path(), "").unwrap();
+
+            let old = backup(user_cache.base_path().join(".manifest.toml.1.backup"), 10 * DAY);
+            let recent = backup(user_cache.base_path().join(".manifest.toml.2.backup"), DAY);
+            let unrestored = backup(user_cache.base_path().join(".dotfiles.3.backup"), 10 * DAY);
+
+            assert!(collect(cache, 7 * DAY, false).unwrap() > 0);
+            assert!(!old.exists());
+            assert!(recent.exists(), "recent backups may still be needed");
+            assert!(unrestored.exists(), "backups that were never restored should be kept");
+        });
+    }
+
+    #[test]
+    fn only_caches_without_manifests_are_removed() {
+        with_cache(|cache| {
+            for username in &["jenny", "bob", "alice"] {
+                fs::create_dir_all(cache.user(*username).dotfiles_path()).unwrap();
+            }
+            fs::write(cache.user("jenny").manifest_path(), "").unwrap();
+
+            cache.change_ledger(true, false, |ledger, _| {
+                ledger.record(ledger::Entry {
+                    path: PathBuf::from("/home/alice/.vimrc"),
+                    target: cache.user("alice").dotfiles_path().join(".vimrc"),
+                    user: "alice".to_owned(),
+                    home: PathBuf::from("/home/alice"),
+                    revision: None,
+                    mode: ledger::Mode::Symlink,
+                    checksum: None,
+                });
+                Ok(())
+            }).unwrap();
+
+            collect(cache, 7 * DAY, false).unwrap();
+            assert!(cache.user("jenny").base_path().exists());
+            assert!(!cache.user("bob").base_path().exists());
+            assert!(cache.user("alice").base_path().exists(), "caches which still have links should be kept");
+        });
+    }
+
+    #[test]
+    fn identities_and_variables_are_kept() {
+        with_cache(|cache| {
+            for username in &["bob", "alice"] {
+                fs::create_dir_all(cache.user(*username).dotfiles_path()).unwrap();
+            }
+            fs::write(cache.user("bob").identity_path(), "AGE-SECRET-KEY-1").unwrap();
+            fs::write(cache.user("alice").vars_path(), "name = \"alice\"").unwrap();
+
+            collect(cache, 7 * DAY, false).unwrap();
+            for username in &["bob", "alice"] {
+                assert!(!cache.user(*username).dotfiles_path().exists());
+            }
+            assert!(cache.user("bob").identity_path().exists(), "identities cannot be recreated");
+            assert!(cache.user("alice").vars_path().exists());
+        });
+    }
+
+    #[test]
+    fn sizes_are_formatted_in_binary_units() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MiB");
+    }
+}
@@ -0,0 +1,100 @@
+use {Dotfile, Error, ErrorKind};
+use {symlink, util};
+use journal::Journal;
+
+use std::io::prelude::*;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::PathBuf;
+use std::fs;
+
+/// A file that polk wrote rather than linked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Generated {
+    /// The path of the generated file.
+    pub path: PathBuf,
+    /// The checksum of the generated contents.
+    pub checksum: String,
+    /// The directories that had to be created to hold the file, outermost first.
+    pub created_directories: Vec<PathBuf>,
+}
+
+/// Writes the generated contents of a dotfile into place.
+///
+/// An existing file is only overwritten if it was previously generated with
+/// the given checksum. Fails with `LinkConflict` if something else is in the way.
+///
+/// If `permissions` is not given, the file is created with the default permissions.
+pub fn write(dotfile: &Dotfile,
+             config: &symlink::Config,
+             contents: &[u8],
+             permissions: Option<u32>,
+             previous_checksum: Option<&str>,
+             journal: &mut Journal) -> Result<Generated, Error> {
+    let dest_path = symlink::path(dotfile, config);
+    journal.track(&dest_path)?;
+
+    if let Ok(metadata) = fs::symlink_metadata(&dest_path) {
+        if metadata.file_type().is_symlink() {
+            // Only a symlink to the dotfile itself was left by polk, such as
+            // when it was linked before being copied.
+            if !symlink::points_to(&dest_path, &dotfile.full_path)? {
+                bail!(ErrorKind::LinkConflict(dest_path, "symlink"));
+            }
+
+            ilog!("there is an existing symlink at '{}', replacing it with a generated file", dest_path.display());
+            fs::remove_file(&dest_path)?;
+        } else if metadata.is_dir() {
+            bail!(ErrorKind::LinkConflict(dest_path, "directory"));
+        } else if previous_checksum != Some(util::checksum_file(&dest_path)?.as_str()) {
+            bail!(ErrorKind::LinkConflict(dest_path, "file"));
+        }
+    }
+
+    let created_directories = match dest_path.parent() {
+        Some(parent) => util::create_directories(parent)?,
+        None => Vec::new(),
+    };
+
+    progress!("{} => {}", dotfile.full_path.display(), dest_path.display());
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    if let Some(permissions) = permissions {
+        // Make sure the contents are never readable with looser permissions,
+        // even for a moment.
+        options.mode(permissions);
+    }
+
+    let mut file = options.open(&dest_path)?;
+
+    if let Some(permissions) = permissions {
+        // The mode is only used when creating a file.
+        file.set_permissions(fs::Permissions::from_mode(permissions))?;
+    }
+
+    file.write_all(contents)?;
+
+    Ok(Generated {
+        path: dest_path,
+        checksum: util::checksum(contents),
+        created_directories,
+    })
+}
+
+/// Copies a dotfile into place.
+///
+/// The copy has the same permissions as the dotfile unless others are given.
+pub fn copy(dotfile: &Dotfile,
+            config: &symlink::Config,
+            permissions: Option<u32>,
+            previous_checksum: Option<&str>,
+            journal: &mut Journal) -> Result<Generated, Error> {
+    let contents = fs::read(&dotfile.full_path)?;
+    let permissions = match permissions {
+        Some(permissions) => permissions,
+        None => fs::metadata(&dotfile.full_path)?.permissions().mode() & 0o7777,
+    };
+
+    self::write(dotfile, config, &contents, Some(permissions), previous_checksum, journal)
+}
@@ -0,0 +1,34 @@
+//! Running the hooks declared by a dotfiles repository.
+
+use {Error, ResultExt};
+
+use std::path::Path;
+use std::process::Command;
+
+/// Runs a hook with `sh -c` from the root of the dotfiles.
+///
+/// Its output is logged rather than written to the terminal, and `$HOME` is
+/// set to the directory the dotfiles are linked into.
+pub fn run(name: &str, command: &str, root_path: &Path, home_path: &Path, verbose: bool) -> Result<(), Error> {
+    ilog!("running the {} hook", name);
+    vlog!(verbose => "$ {}", command);
+
+    let output = Command::new("sh").arg("-c").arg(command).
+        current_dir(root_path).
+        env("HOME", home_path).
+        output().
+        chain_err(|| format!("could not run the {} hook", name))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    for line in stdout.lines().chain(stderr.lines()) {
+        ilog!("{}: {}", name, line);
+    }
+
+    if !output.status.success() {
+        bail!("the {} hook failed ({})", name, output.status);
+    }
+
+    Ok(())
+}
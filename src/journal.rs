@@ -0,0 +1,523 @@
+//! Undoing changes to the filesystem when an operation fails part way.
+//!
+//! Every path is recorded in the journal before it is first touched. If
+//! the operation fails, panics, or is interrupted with `interrupt`, every
+//! recorded path is put back the way it was, most recent first.
+
+use {Error, ResultExt};
+
+use rand::random;
+
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::{fs, io, mem};
+
+/// Set when the user interrupts a journaled operation.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// The number of journaled operations in progress.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// A record of changes that can be rolled back.
+///
+/// A journal that is dropped before being committed, such as when the
+/// operation panics, rolls back everything it recorded.
+#[derive(Debug, Default)]
+pub struct Journal {
+    changes: Vec<Change>,
+    verbose: bool,
+}
+
+/// A single change recorded in a journal.
+#[derive(Debug)]
+enum Change {
+    /// A path that was moved out of the way.
+    ///
+    /// The backup is deleted once the operation succeeds.
+    MovedAside { path: PathBuf, backup_path: PathBuf },
+    /// A path that may have been modified in place.
+    Tracked { path: PathBuf, original: Original },
+    /// A path that was moved somewhere else.
+    Renamed { from: PathBuf, to: PathBuf },
+    /// A path whose permissions were changed from `mode`.
+    ModeChanged { path: PathBuf, mode: u32 },
+}
+
+/// What was at a path before it was touched.
+#[derive(Debug)]
+enum Original {
+    Absent,
+    Symlink(PathBuf),
+    File { contents: Vec<u8>, mode: u32 },
+    Directory { mode: u32 },
+}
+
+/// Counts a journaled operation as in progress for as long as it lives.
+struct Active;
+
+/// Runs an operation, rolling back every change it made if it fails.
+///
+/// Calling `interrupt` while the operation is running makes the next change
+/// it attempts fail, so that everything it has done so far is undone.
+pub fn run<T, F>(verbose: bool, f: F) -> Result<T, Error>
+    where F: FnOnce(&mut Journal) -> Result<T, Error> {
+    let _active = Active::start();
+    let mut journal = Journal { changes: Vec::new(), verbose };
+
+    let result = f(&mut journal).and_then(|value| {
+        journal.check_interrupted()?;
+        Ok(value)
+    });
+
+    match result {
+        Ok(value) => journal.commit().map(|_| value),
+        Err(e) => {
+            ilog!("rolling back changes");
+
+            match journal.rollback() {
+                // Successfully rolled back, propagate error.
+                Ok(..) => Err(e),
+                // Failure during rollback, add more context and propagate up.
+                Err(f) => Err(f).chain_err(|| format!("could not roll back after error: {}", e)),
+            }
+        },
+    }
+}
+
+impl Active {
+    fn start() -> Self {
+        ACTIVE.fetch_add(1, Ordering::SeqCst);
+        Active
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        // Later operations shouldn't be interrupted once everything running has stopped.
+        if ACTIVE.fetch_sub(1, Ordering::SeqCst) == 1 {
+            INTERRUPTED.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Journal {
+    /// Records the current state of a path before it is modified.
+    ///
+    /// Any missing parent directories are recorded too, so that directories
+    /// created to hold the path are removed again. Only the first record of
+    /// a path is kept.
+    pub fn track(&mut self, path: &Path) -> Result<(), Error> {
+        self.check_interrupted()?;
+
+        let mut missing: Vec<_> = path.ancestors().skip(1).take_while(|p| fs::symlink_metadata(p).is_err()).collect();
+        missing.reverse();
+
+        for ancestor in missing {
+            self.record(ancestor)?;
+        }
+
+        self.record(path)
+    }
+
+    /// Moves a path out of the way, restoring it if the operation fails.
+    pub fn move_aside(&mut self, path: &Path) -> Result<(), Error> {
+        self.check_interrupted()?;
+
+        if fs::symlink_metadata(path).is_err() {
+            // Nothing to back up in that case, but anything created there
+            // should still be removed.
+            return self.track(path);
+        }
+
+        let backup_name = match path.file_name() {
+            Some(file_name) => {
+                let mut backup_name = OsString::from(".");
+                backup_name.push(file_name);
+                backup_name.push(format!(".{}.backup", self::random_token()));
+                backup_name
+            },
+            None => bail!("cannot move '{}' aside as it has no file name", path.display()),
+        };
+        let backup_path = path.with_file_name(backup_name);
+
+        ilog!("backing up {} to {}", path.display(), backup_path.display());
+        fs::rename(path, &backup_path).chain_err(|| "failed to move file to temporary path")?;
+
+        self.changes.push(Change::MovedAside { path: path.to_owned(), backup_path });
+        Ok(())
+    }
+
+    /// Moves a path somewhere else, moving it back if the operation fails.
+    ///
+    /// The destination must not exist.
+    pub fn rename(&mut self, from: &Path, to: &Path) -> Result<(), Error> {
+        self.check_interrupted()?;
+
+        if fs::symlink_metadata(to).is_ok() {
+            bail!("cannot move '{}' to '{}' as it already exists", from.display(), to.display());
+        }
+
+        fs::rename(from, to).chain_err(|| format!("could not move '{}' to '{}'", from.display(), to.display()))?;
+        self.changes.push(Change::Renamed { from: from.to_owned(), to: to.to_owned() });
+        Ok(())
+    }
+
+    /// Changes the permissions of a path, changing them back if the operation fails.
+    pub fn set_mode(&mut self, path: &Path, mode: u32) -> Result<(), Error> {
+        self.check_interrupted()?;
+
+        let original = fs::metadata(path)?.permissions().mode() & 0o7777;
+
+        if original == mode {
+            return Ok(());
+        }
+
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).
+            chain_err(|| format!("could not set permissions of '{}'", path.display()))?;
+        self.changes.push(Change::ModeChanged { path: path.to_owned(), mode: original });
+        Ok(())
+    }
+
+    /// Fails if the user has asked for the operation to stop.
+    fn check_interrupted(&self) -> Result<(), Error> {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            bail!("interrupted");
+        }
+
+        Ok(())
+    }
+
+    /// Records what is at a path, unless it has already been recorded.
+    fn record(&mut self, path: &Path) -> Result<(), Error> {
+        let already_recorded = self.changes.iter().any(|change| match *change {
+            // Everything beneath a moved path is restored along with it.
+            Change::MovedAside { path: ref p, .. } | Change::Renamed { to: ref p, .. } => path.starts_with(p),
+            Change::Tracked { path: ref p, .. } => path == p,
+            // The contents still need recording.
+            Change::ModeChanged { .. } => false,
+        });
+
+        if already_recorded {
+            return Ok(());
+        }
+
+        let original = match fs::symlink_metadata(path) {
+            Ok(metadata) => {
+                let mode = metadata.permissions().mode() & 0o7777;
+
+                if metadata.file_type().is_symlink() {
+                    Original::Symlink(fs::read_link(path)?)
+                } else if metadata.is_dir() {
+                    Original::Directory { mode }
+                } else {
+                    Original::File { contents: fs::read(path)?, mode }
+                }
+            },
+            // Nothing can exist beneath a file either.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound || e.kind() == io::ErrorKind::NotADirectory => {
+                Original::Absent
+            },
+            Err(e) => return Err(e).chain_err(|| format!("could not read '{}'", path.display())),
+        };
+
+        self.changes.push(Change::Tracked { path: path.to_owned(), original });
+        Ok(())
+    }
+
+    /// Forgets every change, deleting anything that was moved aside.
+    fn commit(mut self) -> Result<(), Error> {
+        for change in mem::take(&mut self.changes) {
+            if let Change::MovedAside { backup_path, .. } = change {
+                vlog!(self.verbose => "deleting backup {}", backup_path.display());
+                self::remove(&backup_path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Puts every recorded path back the way it was.
+    ///
+    /// Rolling back carries on past failures so that as much as possible is
+    /// restored. The first failure is returned.
+    fn rollback(mut self) -> Result<(), Error> {
+        self.undo()
+    }
+
+    /// Puts every recorded path back the way it was, forgetting the changes.
+    fn undo(&mut self) -> Result<(), Error> {
+        let verbose = self.verbose;
+        let mut result = Ok(());
+
+        for change in mem::take(&mut self.changes).into_iter().rev() {
+            let restored = match change {
+                Change::MovedAside { path, backup_path } => {
+                    ilog!("restoring {} to {}", backup_path.display(), path.display());
+                    self::restore_moved(&path, &backup_path)
+                },
+                Change::Renamed { from, to } => {
+                    vlog!(verbose => "moving {} back to {}", to.display(), from.display());
+                    fs::rename(&to, &from).
+                        chain_err(|| format!("could not move '{}' back to '{}'", to.display(), from.display()))
+                },
+                // Anything removed since is restored by an earlier change.
+                Change::ModeChanged { ref path, .. } if fs::symlink_metadata(path).is_err() => Ok(()),
+                Change::ModeChanged { path, mode } => {
+                    vlog!(verbose => "restoring the mode of {} to {:o}", path.display(), mode);
+                    fs::set_permissions(&path, fs::Permissions::from_mode(mode)).
+                        chain_err(|| format!("could not restore the permissions of '{}'", path.display()))
+                },
+                Change::Tracked { path, original } => {
+                    vlog!(verbose => "restoring {}", path.display());
+                    self::restore(&path, &original).
+                        chain_err(|| format!("could not restore '{}'", path.display()))
+                },
+            };
+
+            if result.is_ok() {
+                result = restored;
+            }
+        }
+
+        result
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        if self.changes.is_empty() {
+            return;
+        }
+
+        ilog!("rolling back changes");
+
+        if let Err(e) = self.undo() {
+            warn!("could not roll back: {}", e);
+        }
+    }
+}
+
+/// Moves a path back from where it was moved aside to.
+fn restore_moved(path: &Path, backup_path: &Path) -> Result<(), Error> {
+    // If somebody has put a file in our place, delete it.
+    if fs::symlink_metadata(path).is_ok() {
+        self::remove(path).chain_err(|| "could not remove dirty file")?;
+    }
+
+    fs::rename(backup_path, path).chain_err(|| "could not move backup file to original path")?;
+    Ok(())
+}
+
+/// Puts a path back to its original state.
+fn restore(path: &Path, original: &Original) -> Result<(), Error> {
+    let current = fs::symlink_metadata(path).ok();
+
+    match *original {
+        Original::Absent => {
+            match current {
+                // Only directories we created are removed, never their contents.
+                Some(ref metadata) if metadata.is_dir() => fs::remove_dir(path)?,
+                Some(..) => fs::remove_file(path)?,
+                None => (),
+            }
+        },
+        Original::Symlink(ref target) => {
+            if fs::read_link(path).ok().as_ref() != Some(target) {
+                if current.is_some() {
+                    self::remove_entry(path)?;
+                }
+
+                unix::fs::symlink(target, path)?;
+            }
+        },
+        Original::File { ref contents, mode } => {
+            let is_file = current.map(|m| m.file_type().is_file()).unwrap_or(false);
+
+            if !is_file || fs::read(path)? != *contents {
+                if fs::symlink_metadata(path).is_ok() {
+                    self::remove_entry(path)?;
+                }
+
+                let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(mode).open(path)?;
+                file.write_all(contents)?;
+            }
+
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        },
+        Original::Directory { mode } => {
+            match current {
+                Some(ref metadata) if metadata.is_dir() => (),
+                Some(..) => {
+                    fs::remove_file(path)?;
+                    fs::create_dir(path)?;
+                },
+                None => fs::create_dir(path)?,
+            }
+
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        },
+    }
+
+    Ok(())
+}
+
+/// Removes a file, symlink or empty directory.
+fn remove_entry(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Removes a file or symlink, or a directory and everything inside it.
+fn remove(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Finds the paths inside a directory that were moved aside and never restored or deleted.
+///
+/// These are left behind when polk is killed part way through an operation.
+/// Returns each backup along with the path it was moved aside from.
+pub fn backups(directory: &Path) -> Result<Vec<(PathBuf, PathBuf)>, Error> {
+    let mut backups = Vec::new();
+
+    if !directory.is_dir() {
+        return Ok(backups);
+    }
+
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+
+        if let Some(original) = self::backup_of(&path) {
+            backups.push((path, original));
+        }
+    }
+
+    backups.sort();
+    Ok(backups)
+}
+
+/// Gets the path that a backup was moved aside from.
+pub fn backup_of(backup_path: &Path) -> Option<PathBuf> {
+    let file_name = backup_path.file_name()?.as_bytes();
+    let name = file_name.strip_prefix(b".")?.strip_suffix(b".backup")?;
+    let separator = name.iter().rposition(|&b| b == b'.')?;
+    let (original_name, token) = (&name[..separator], &name[separator + 1..]);
+
+    if original_name.is_empty() || token.is_empty() || !token.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+
+    Some(backup_path.with_file_name(OsStr::from_bytes(original_name)))
+}
+
+/// Interrupts every journaled operation in progress, rolling them back.
+///
+/// This is meant to be called from a Ctrl-C handler. Returns `false` if
+/// nothing was running, in which case there is nothing to roll back.
+pub fn interrupt() -> bool {
+    if ACTIVE.load(Ordering::SeqCst) > 0 {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        true
+    } else {
+        false
+    }
+}
+
+/// Generates a random token text.
+fn random_token() -> String {
+    let (a,b): (u32,u32) = (random(), random());
+    format!("{}{}", a, b)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testing::temp_directory;
+
+    use std::panic;
+
+    #[test]
+    fn failures_are_rolled_back() {
+        let dir = temp_directory("journal");
+        fs::write(dir.join("file"), "original").unwrap();
+        unix::fs::symlink("/nonexistent", dir.join("link")).unwrap();
+
+        let result: Result<(), Error> = run(false, |journal| {
+            journal.track(&dir.join("file"))?;
+            fs::write(dir.join("file"), "modified")?;
+
+            journal.track(&dir.join("link"))?;
+            fs::remove_file(dir.join("link"))?;
+
+            journal.track(&dir.join("a/b/new"))?;
+            fs::create_dir_all(dir.join("a/b"))?;
+            fs::write(dir.join("a/b/new"), "new")?;
+
+            bail!("failure");
+        });
+
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(dir.join("file")).unwrap(), "original");
+        assert_eq!(fs::read_link(dir.join("link")).unwrap(), Path::new("/nonexistent"));
+        assert!(!dir.join("a").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn panics_are_rolled_back() {
+        let dir = temp_directory("journal");
+        fs::write(dir.join("file"), "original").unwrap();
+
+        let result = panic::catch_unwind(|| {
+            run(false, |journal| -> Result<(), Error> {
+                journal.track(&dir.join("file"))?;
+                fs::write(dir.join("file"), "modified")?;
+                panic!("failure");
+            })
+        });
+
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(dir.join("file")).unwrap(), "original");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backups_are_recognised() {
+        assert_eq!(backup_of(Path::new("/a/.dotfiles.12345.backup")), Some(PathBuf::from("/a/dotfiles")));
+        assert_eq!(backup_of(Path::new("/a/.dotfiles.backup")), None);
+        assert_eq!(backup_of(Path::new("/a/.dotfiles.abc.backup")), None);
+        assert_eq!(backup_of(Path::new("/a/dotfiles")), None);
+
+        let backup = OsStr::from_bytes(b"/a/.\xff.12345.backup");
+        assert_eq!(backup_of(Path::new(backup)), Some(PathBuf::from(OsStr::from_bytes(b"/a/\xff"))));
+    }
+
+    #[test]
+    fn successes_are_kept() {
+        let dir = temp_directory("journal");
+        fs::create_dir(dir.join("moved")).unwrap();
+
+        run(false, |journal| {
+            journal.move_aside(&dir.join("moved"))?;
+            fs::write(dir.join("moved"), "replacement")?;
+            Ok(())
+        }).unwrap();
+
+        assert_eq!(fs::read_to_string(dir.join("moved")).unwrap(), "replacement");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
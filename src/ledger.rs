@@ -0,0 +1,365 @@
+use {Error, ResultExt};
+use journal::Journal;
+use util;
+
+use toml;
+
+use std::cmp::Reverse;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::fs;
+
+/// How a link was deployed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// A symbolic link.
+    Symlink,
+    /// A file generated by rendering a template.
+    Rendered,
+    /// A file generated by decrypting a secret.
+    Decrypted,
+    /// A copy of a dotfile.
+    Copied,
+}
+
+/// A single link that polk created.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// The path of the link itself.
+    pub path: PathBuf,
+    /// The path the link points to.
+    ///
+    /// For generated files, this is the template or secret they were generated from.
+    pub target: PathBuf,
+    /// The user whose dotfiles the link was created for.
+    pub user: String,
+    /// The directory the link was created relative to.
+    pub home: PathBuf,
+    /// The revision of the dotfiles at the time the link was created.
+    pub revision: Option<String>,
+    /// How the link was deployed.
+    pub mode: Mode,
+    /// The checksum of the contents of a generated file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+}
+
+/// A directory that polk created to hold links.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Directory {
+    /// The path of the directory.
+    pub path: PathBuf,
+    /// The user whose dotfiles the directory was created for.
+    pub user: String,
+    /// The directory the links were being created relative to.
+    pub home: PathBuf,
+}
+
+/// A persistent record of every link polk has created.
+///
+/// Links are only ever removed if they still point to where polk
+/// put them, so that files placed by the user are never touched.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ledger {
+    /// All of the links, in the order they were created.
+    #[serde(default, rename = "link")]
+    pub links: Vec<Entry>,
+    /// All of the directories created to hold links.
+    ///
+    /// Directories which already existed are never recorded.
+    #[serde(default, rename = "directory")]
+    pub directories: Vec<Directory>,
+}
+
+impl Ledger {
+    /// Loads the ledger from disk.
+    ///
+    /// Returns an empty ledger if it does not exist yet.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        if !path.exists() {
+            return Ok(Ledger::default());
+        }
+
+        let mut file = fs::File::open(path)?;
+        let mut ledger_toml = String::new();
+        file.read_to_string(&mut ledger_toml)?;
+
+        toml::from_str(&ledger_toml).chain_err(|| format!("could not parse link ledger '{}'", path.display()))
+    }
+
+    /// Saves the ledger to disk.
+    ///
+    /// The old ledger is kept intact until the new one has been written in full.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let ledger_toml = toml::to_string(self).chain_err(|| "failed to create ledger toml")?;
+
+        util::write_atomically(path, ledger_toml.as_bytes()).
+            chain_err(|| format!("could not write link ledger '{}'", path.display()))
+    }
+
+    /// Records a newly created link, replacing any previous record at the same path.
+    pub fn record(&mut self, entry: Entry) {
+        self.links.retain(|e| e.path != entry.path);
+        self.links.push(entry);
+    }
+
+    /// Gets the record of the link at a path.
+    pub fn entry(&self, path: &Path) -> Option<&Entry> {
+        self.links.iter().find(|e| e.path == path)
+    }
+
+    /// Records a directory that was created to hold a link.
+    pub fn record_directory(&mut self, directory: Directory) {
+        if !self.directories.iter().any(|d| d.path == directory.path) {
+            self.directories.push(directory);
+        }
+    }
+
+    /// Removes every recorded directory that no longer contains anything.
+    ///
+    /// Directories are considered regardless of which user they were created
+    /// for, as an empty directory no longer holds anybody's links.
+    pub fn remove_empty_directories(&mut self, journal: &mut Journal, verbose: bool) -> Result<(), Error> {
+        // Deepest directories first so that emptied parents can be removed too.
+        self.directories.sort_by_key(|d| Reverse(d.path.components().count()));
+
+        let mut result = Ok(());
+
+        self.directories.retain(|directory| {
+            // Keep track of everything after the first failure.
+            if result.is_err() { return true; }
+
+            match directory.remove_if_empty(journal, verbose) {
+                Ok(removed) => !removed,
+                Err(e) => {
+                    result = Err(e);
+                    true
+                },
+            }
+        });
+
+        result
+    }
+
+    /// Removes the links matching a predicate from disk and from the ledger.
+    ///
+    /// Links which no longer point to where polk put them are forgotten
+    /// but left in place.
+    pub fn remove_links<F>(&mut self, journal: &mut Journal, verbose: bool, predicate: F) -> Result<(), Error>
+        where F: FnMut(&Entry) -> bool {
+        let (mut matching, remaining): (Vec<_>, Vec<_>) = self.links.drain(..).partition(predicate);
+        self.links = remaining;
+
+        while let Some(entry) = matching.pop() {
+            match entry.remove(journal, verbose) {
+                Ok(..) => (),
+                Err(e) => {
+                    // Keep track of the links we didn't get around to removing.
+                    self.links.extend(matching);
+                    self.links.push(entry);
+                    return Err(e);
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Directory {
+    /// Removes the directory if it is empty.
+    ///
+    /// Returns `true` if the directory is now gone.
+    fn remove_if_empty(&self, journal: &mut Journal, verbose: bool) -> Result<bool, Error> {
+        if !self.path.is_dir() {
+            // Somebody else already removed it.
+            return Ok(true);
+        }
+
+        if fs::read_dir(&self.path)?.next().is_some() {
+            return Ok(false);
+        }
+
+        vlog!(verbose => "deleting empty directory {}", self.path.display());
+        journal.track(&self.path)?;
+        fs::remove_dir(&self.path).chain_err(|| format!("could not remove directory '{}'", self.path.display()))?;
+        Ok(true)
+    }
+}
+
+impl Entry {
+    /// Checks if the link is still where polk put it.
+    ///
+    /// Generated files must not have been modified since they were written.
+    pub fn is_intact(&self) -> bool {
+        match self.mode {
+            Mode::Symlink => match fs::read_link(&self.path) {
+                Ok(current_target) => current_target == self.target,
+                Err(..) => false,
+            },
+            Mode::Rendered | Mode::Decrypted | Mode::Copied => {
+                let is_file = fs::symlink_metadata(&self.path).map(|m| m.file_type().is_file()).unwrap_or(false);
+
+                is_file && util::checksum_file(&self.path).ok() == self.checksum
+            },
+        }
+    }
+
+    /// Removes the link from disk if it is intact.
+    fn remove(&self, journal: &mut Journal, verbose: bool) -> Result<(), Error> {
+        if self.is_intact() {
+            vlog!(verbose => "deleting {}", self.path.display());
+            journal.track(&self.path)?;
+            fs::remove_file(&self.path).chain_err(|| format!("could not remove link '{}'", self.path.display()))?;
+        } else if fs::symlink_metadata(&self.path).is_ok() {
+            match self.mode {
+                Mode::Symlink => {
+                    warn!("'{}' no longer points to '{}', leaving it alone",
+                          self.path.display(), self.target.display());
+                },
+                Mode::Rendered | Mode::Decrypted | Mode::Copied => {
+                    warn!("'{}' has been modified since it was written, leaving it alone",
+                          self.path.display());
+                },
+            }
+        } else {
+            vlog!(verbose => "{} has already been removed", self.path.display());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use journal;
+    use testing::temp_directory;
+
+    use std::os::unix;
+
+    fn entry(path: PathBuf, target: &str, mode: Mode) -> Entry {
+        Entry {
+            path,
+            target: PathBuf::from(target),
+            user: "jenny".to_owned(),
+            home: PathBuf::from("/home/jenny"),
+            revision: None,
+            mode,
+            checksum: None,
+        }
+    }
+
+    fn directory(path: PathBuf) -> Directory {
+        Directory { path, user: "jenny".to_owned(), home: PathBuf::from("/home/jenny") }
+    }
+
+    fn remove_all_links(ledger: &mut Ledger) {
+        journal::run(false, |journal| ledger.remove_links(journal, false, |_| true)).unwrap();
+    }
+
+    #[test]
+    fn saving_replaces_the_ledger_in_one_go() {
+        let dir = temp_directory("ledger");
+        let path = dir.join("ledger.toml");
+        fs::write(&path, "not a ledger").unwrap();
+
+        let mut ledger = Ledger::default();
+        ledger.record(entry(dir.join(".vimrc"), "/dotfiles/.vimrc", Mode::Symlink));
+        ledger.save(&path).unwrap();
+
+        assert_eq!(Ledger::load(&path).unwrap(), ledger);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1, "no temporary files should be left behind");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn intact_links_are_removed() {
+        let dir = temp_directory("ledger");
+        unix::fs::symlink("/dotfiles/.vimrc", dir.join(".vimrc")).unwrap();
+
+        let mut ledger = Ledger::default();
+        ledger.record(entry(dir.join(".vimrc"), "/dotfiles/.vimrc", Mode::Symlink));
+        remove_all_links(&mut ledger);
+
+        assert!(fs::symlink_metadata(dir.join(".vimrc")).is_err());
+        assert!(ledger.links.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn retargeted_links_are_kept() {
+        let dir = temp_directory("ledger");
+        unix::fs::symlink("/elsewhere/.vimrc", dir.join(".vimrc")).unwrap();
+
+        let mut ledger = Ledger::default();
+        ledger.record(entry(dir.join(".vimrc"), "/dotfiles/.vimrc", Mode::Symlink));
+        remove_all_links(&mut ledger);
+
+        assert_eq!(fs::read_link(dir.join(".vimrc")).unwrap(), Path::new("/elsewhere/.vimrc"));
+        assert!(ledger.links.is_empty(), "links that are no longer ours should be forgotten");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn links_replaced_by_files_are_kept() {
+        let dir = temp_directory("ledger");
+        fs::write(dir.join(".vimrc"), "set ruler").unwrap();
+
+        let mut ledger = Ledger::default();
+        ledger.record(entry(dir.join(".vimrc"), "/dotfiles/.vimrc", Mode::Symlink));
+        remove_all_links(&mut ledger);
+
+        assert_eq!(fs::read_to_string(dir.join(".vimrc")).unwrap(), "set ruler");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn modified_generated_files_are_kept() {
+        let dir = temp_directory("ledger");
+        fs::write(dir.join(".gitconfig"), "[user]").unwrap();
+        fs::write(dir.join(".npmrc"), "registry").unwrap();
+
+        let mut gitconfig = entry(dir.join(".gitconfig"), "/dotfiles/.gitconfig.tmpl", Mode::Rendered);
+        gitconfig.checksum = Some(util::checksum_file(&dir.join(".gitconfig")).unwrap());
+        let mut npmrc = entry(dir.join(".npmrc"), "/dotfiles/.npmrc", Mode::Copied);
+        npmrc.checksum = Some(util::checksum_file(&dir.join(".npmrc")).unwrap());
+        fs::write(dir.join(".npmrc"), "modified").unwrap();
+
+        let mut ledger = Ledger::default();
+        ledger.record(gitconfig);
+        ledger.record(npmrc);
+        remove_all_links(&mut ledger);
+
+        assert!(!dir.join(".gitconfig").exists());
+        assert_eq!(fs::read_to_string(dir.join(".npmrc")).unwrap(), "modified");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_empty_recorded_directories_are_removed() {
+        let dir = temp_directory("ledger");
+        fs::create_dir_all(dir.join("created/empty")).unwrap();
+        fs::create_dir_all(dir.join("used")).unwrap();
+        fs::write(dir.join("used/file"), "").unwrap();
+        fs::create_dir_all(dir.join("existing")).unwrap();
+
+        let mut ledger = Ledger::default();
+        ledger.record_directory(directory(dir.join("created")));
+        ledger.record_directory(directory(dir.join("created/empty")));
+        ledger.record_directory(directory(dir.join("used")));
+        journal::run(false, |journal| ledger.remove_empty_directories(journal, false)).unwrap();
+
+        assert!(!dir.join("created").exists(), "emptied parents should be removed too");
+        assert!(dir.join("used/file").exists());
+        assert!(dir.join("existing").exists(), "directories polk did not create should be left alone");
+        assert_eq!(ledger.directories, vec![directory(dir.join("used"))]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
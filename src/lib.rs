@@ -0,0 +1,132 @@
+//! Polk manages dotfiles by linking them into a home directory from a
+//! repository.
+//!
+//! Everything starts with a `Cache`, which holds the dotfiles of every user
+//! polk knows about, usually in `~/.local/share/polk`.
+//!
+//! ```no_run
+//! extern crate polk;
+//!
+//! use polk::{Cache, Environment, SourceSpec};
+//! use polk::symlink;
+//!
+//! # fn main() -> Result<(), polk::Error> {
+//! // Or `Environment::current()` for the user running the process.
+//! let cache = Cache::at(Environment::new("/home/jenny", "jenny"))?;
+//! let mut user_cache = cache.user("jenny");
+//!
+//! // Download the dotfiles, then link them into a home directory.
+//! let source: SourceSpec = "github:jenny/dotfiles".parse()?;
+//! user_cache.grab(&source, false)?;
+//!
+//! let config = symlink::Config::new(&cache.environment);
+//! user_cache.link_ext(&config, true, false)?;
+//!
+//! let dotfiles = user_cache.dotfiles()?;
+//! for status in user_cache.status(&dotfiles, &config)? {
+//!     println!("{}: {}", status.destination.display(), status.state.label());
+//! }
+//!
+//! // Later on, fetch new dotfiles.
+//! user_cache.update(true, false)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Polk never prints anything or exits the process. Messages are sent to
+//! the sink installed with `log::set_sink`, and errors are returned as
+//! `Error`s whose `ErrorKind` says what went wrong.
+
+extern crate git2;
+extern crate regex;
+#[macro_use]
+extern crate lazy_static;
+extern crate walkdir;
+extern crate term;
+extern crate toml;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate error_chain;
+extern crate rand;
+extern crate symlink as sym;
+extern crate hostname;
+extern crate sha2;
+extern crate age;
+extern crate globset;
+extern crate ignore;
+extern crate similar;
+
+pub use self::cache::{Cache, UserCache};
+pub use self::source::{Source, SourceSpec};
+pub use self::feature::FeatureSet;
+pub use self::environment::Environment;
+pub use self::errors::{Error, ErrorKind, ResultExt};
+
+#[macro_use]
+pub mod log;
+pub mod cache;
+pub mod source;
+pub mod symlink;
+pub mod feature;
+pub mod backend;
+pub mod util;
+pub mod environment;
+pub mod errors;
+pub mod ledger;
+pub mod journal;
+pub mod mapping;
+pub mod permissions;
+pub mod repository;
+pub mod template;
+pub mod secret;
+pub mod generated;
+pub mod status;
+pub mod diff;
+pub mod doctor;
+pub mod gc;
+pub mod config;
+pub mod lock;
+pub mod manifest;
+pub mod hook;
+pub mod polkignore;
+
+#[cfg(test)]
+mod testing;
+
+use std::path::PathBuf;
+
+/// A single dotfile.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dotfile
+{
+    /// The full on-disk path of the dotfile.
+    pub full_path: PathBuf,
+    /// The path of the dotfile relative to the users home directory.
+    ///
+    /// This is the path in the repository after any path mapping rules
+    /// have been applied.
+    pub relative_path: PathBuf,
+    /// Whether the dotfile is a single file or an entire directory.
+    pub kind: DotfileKind,
+}
+
+/// The kind of thing a dotfile refers to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DotfileKind {
+    /// A regular file.
+    File,
+    /// A directory which is symlinked as a single unit.
+    ///
+    /// Created by placing a `.polk-link-dir` marker file inside the directory.
+    Directory,
+    /// A template which is rendered into a generated file rather than linked.
+    ///
+    /// Templates have a `.tmpl` extension, which is not part of the generated file name.
+    Template,
+    /// An encrypted file which is decrypted into place rather than linked.
+    ///
+    /// Secrets have a `.age` extension, which is not part of the decrypted file name.
+    Secret,
+}
@@ -0,0 +1,101 @@
+//! Keeping polk processes from changing the same cache at the same time.
+//!
+//! Locks are advisory locks on files, which the operating system releases
+//! when the process holding them exits, even if it is killed.
+
+use {Error, ErrorKind, ResultExt};
+
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::path::Path;
+
+/// A lock on one or more files, released when dropped.
+#[derive(Debug)]
+pub struct Lock {
+    files: Vec<File>,
+}
+
+/// How a lock is shared with other processes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// Any number of processes can hold a shared lock at once.
+    Shared,
+    /// Nobody else can hold any lock while an exclusive lock is held.
+    Exclusive,
+}
+
+/// Locks a file, creating it if it does not exist.
+///
+/// If another process holds a conflicting lock, this waits for it to be
+/// released when `wait` is set, and fails with `Locked` otherwise.
+pub fn acquire(path: &Path, kind: Kind, wait: bool) -> Result<Lock, Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path).
+        chain_err(|| format!("could not open lock file '{}'", path.display()))?;
+
+    let result = match kind {
+        Kind::Shared => file.try_lock_shared(),
+        Kind::Exclusive => file.try_lock(),
+    };
+
+    match result {
+        Ok(()) => (),
+        Err(TryLockError::WouldBlock) if wait => {
+            ilog!("waiting for another polk process to release '{}'", path.display());
+
+            match kind {
+                Kind::Shared => file.lock_shared(),
+                Kind::Exclusive => file.lock(),
+            }.chain_err(|| format!("could not lock '{}'", path.display()))?;
+        },
+        Err(TryLockError::WouldBlock) => bail!(ErrorKind::Locked(path.to_owned())),
+        Err(TryLockError::Error(e)) => return Err(e).chain_err(|| format!("could not lock '{}'", path.display())),
+    }
+
+    Ok(Lock { files: vec![file] })
+}
+
+impl Lock {
+    /// Holds another lock along with this one, releasing both together.
+    pub fn join(mut self, other: Lock) -> Lock {
+        self.files.extend(other.files);
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testing::temp_directory;
+
+    #[test]
+    fn shared_locks_can_be_held_together() {
+        let dir = temp_directory("lock");
+        let path = dir.join("polk.lock");
+
+        let _first = acquire(&path, Kind::Shared, false).unwrap();
+        let _second = acquire(&path, Kind::Shared, false).unwrap();
+        assert!(acquire(&path, Kind::Exclusive, false).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn held_locks_fail_without_waiting() {
+        let dir = temp_directory("lock");
+        let path = dir.join("polk.lock");
+
+        let lock = acquire(&path, Kind::Exclusive, false).unwrap();
+        match acquire(&path, Kind::Exclusive, false) {
+            Err(Error(ErrorKind::Locked(ref locked_path), _)) => assert_eq!(locked_path, &path),
+            other => panic!("expected the lock to be held, got {:?}", other),
+        }
+
+        drop(lock);
+        assert!(acquire(&path, Kind::Exclusive, false).is_ok(), "dropping a lock should release it");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
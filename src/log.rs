@@ -1,94 +1,92 @@
+//! Sending log messages to whoever is embedding polk.
+//!
+//! Polk never writes to the terminal itself. Messages are discarded until a
+//! sink is installed with `set_sink`.
+
+use std::fmt;
+use std::sync::RwLock;
+
+/// How important a log message is.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Level {
+    /// Details that are only logged when verbose output is requested.
+    Verbose,
+    /// General information about what polk is doing.
+    Info,
+    /// Something that probably needs the user's attention.
+    Warning,
+    /// A change polk made, such as a link it created.
+    Progress,
+}
+
+/// Somewhere to send log messages.
+///
+/// Closures taking a `Level` and a message are sinks too.
+pub trait Sink: Send + Sync {
+    /// Handles a single log message.
+    fn log(&self, level: Level, message: &str);
+}
+
+impl<F> Sink for F where F: Fn(Level, &str) + Send + Sync {
+    fn log(&self, level: Level, message: &str) {
+        self(level, message)
+    }
+}
+
+lazy_static! {
+    /// The sink that messages are sent to, if there is one.
+    static ref SINK: RwLock<Option<Box<dyn Sink>>> = RwLock::new(None);
+}
+
+/// Sends every log message from now on to a sink.
+pub fn set_sink<S>(sink: S) where S: Sink + 'static {
+    *SINK.write().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(sink));
+}
+
+/// Sends a message to the sink.
+#[doc(hidden)]
+pub fn emit(level: Level, message: fmt::Arguments) {
+    let sink = SINK.read().unwrap_or_else(|e| e.into_inner());
+
+    if let Some(ref sink) = *sink {
+        sink.log(level, &message.to_string());
+    }
+}
 
 /// Log a message if verbosity is enabled.
+#[doc(hidden)]
+#[macro_export]
 macro_rules! vlog {
     ($verbosity_enabled:expr => $fmt:expr $(, $arg:expr )*) => {
-        log!("[log]", YELLOW, $verbosity_enabled => $fmt $(, $arg )* );
+        if $verbosity_enabled {
+            $crate::log::emit($crate::log::Level::Verbose, format_args!($fmt $(, $arg )*));
+        }
     }
 }
 
 /// Logs an information message unconditionally.
+#[doc(hidden)]
+#[macro_export]
 macro_rules! ilog {
     ($fmt:expr $(, $arg:expr )*) => {
-        log!("[info]", BRIGHT_BLUE, true => $fmt $(, $arg )* );
+        $crate::log::emit($crate::log::Level::Info, format_args!($fmt $(, $arg )*))
     }
 }
 
 /// Logs a warning message unconditionally.
+#[doc(hidden)]
+#[macro_export]
 macro_rules! warn {
     ($fmt:expr $(, $arg:expr )*) => {
-        log!("warning", MAGENTA, true => $fmt $(, $arg )* );
+        $crate::log::emit($crate::log::Level::Warning, format_args!($fmt $(, $arg )*))
     }
 }
 
-/// Log an error message and terminate the process.
-macro_rules! fatal {
+/// Logs a change made to the filesystem.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! progress {
     ($fmt:expr $(, $arg:expr )*) => {
-        {
-            log!("error", RED, true => $fmt $(, $arg )* );
-            ::std::process::exit(1);
-        }
-    }
-}
-
-/// Prints out a fatal `Error` and an explanation message then
-/// terminates the process.
-macro_rules! fatal_error {
-    ($error:expr) => {
-        {
-            use std::io::prelude::*;
-            use std::io;
-
-            let error: $crate::Error = $error.into();
-            let errors: Vec<_> = error.iter().map(ToString::to_string).collect();
-
-            // Print pretty error messages in release builds.
-            #[cfg(not(debug_assertions))]
-            {
-                fatal!("{}", error)
-            }
-
-            // Print useful stacktrace in debug mode.
-            #[cfg(debug_assertions)]
-            {
-                writeln!(io::stderr(), "error: {}", errors.join(" - ")).unwrap();
-                panic!("{}", error);
-            }
-        }
-    };
-
-    ($error:expr, $message:expr) => {
-        {
-            use $crate::ResultExt;
-
-            // We can only chain `Result`, not `Error`.
-            let error: Result<(), $crate::Error> = Err($error.into());
-            let error = error.chain_err(|| $message);
-            let error = error.err().unwrap();
-
-            fatal_error!(error);
-        }
-    };
-}
-
-/// Write a generic log message to standard error.
-macro_rules! log {
-    ($label:expr, $color:ident,
-     $logging_enabled:expr => $fmt:expr $(, $arg:expr )*) => {
-        {
-            use std::io;
-            use std::io::prelude::*;
-
-            if $logging_enabled {
-                use term;
-
-                let mut t = term::stderr().unwrap();
-
-                t.fg(term::color::$color).ok();
-                write!(io::stderr(), "{}: ", $label).ok();
-                t.reset().ok();
-                writeln!(io::stderr(), $fmt $( , $arg )*).ok();
-            }
-        }
+        $crate::log::emit($crate::log::Level::Progress, format_args!($fmt $(, $arg )*))
     }
 }
-
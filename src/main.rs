@@ -1,62 +1,127 @@
-extern crate clap;
-extern crate git2;
-extern crate regex;
 #[macro_use]
-extern crate lazy_static;
-extern crate walkdir;
+extern crate polk;
+extern crate clap;
 extern crate term;
-extern crate toml;
-extern crate serde;
+extern crate ctrlc;
 #[macro_use]
-extern crate serde_derive;
-#[macro_use]
-extern crate error_chain;
-extern crate rand;
-extern crate symlink as sym;
+extern crate serde_json;
+
+/// Log an error message and terminate the process.
+macro_rules! fatal {
+    (exit $exit_code:expr => $fmt:expr $(, $arg:expr )*) => {
+        {
+            log!("error", RED, true => $fmt $(, $arg )* );
+            ::std::process::exit($exit_code);
+        }
+    };
+    ($fmt:expr $(, $arg:expr )*) => {
+        fatal!(exit 1 => $fmt $(, $arg )*)
+    };
+}
 
-pub use self::cache::{Cache, UserCache};
-pub use self::source::{Source, SourceSpec};
-pub use self::feature::FeatureSet;
-pub use self::errors::{Error, ErrorKind, ResultExt};
+/// Prints out a fatal `Error` and an explanation message then
+/// terminates the process.
+macro_rules! fatal_error {
+    (exit $exit_code:expr => $error:expr) => {
+        {
+            let error: $crate::Error = $error.into();
+
+            // Print pretty error messages in release builds.
+            #[cfg(not(debug_assertions))]
+            {
+                fatal!(exit $exit_code => "{}", error)
+            }
 
-#[macro_use]
-pub mod log;
-pub mod cache;
-pub mod source;
-pub mod symlink;
-pub mod feature;
-pub mod backend;
-pub mod tools;
-pub mod util;
-pub mod errors;
-
-/// A single dotfile.
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub struct Dotfile
-{
-    /// The full on-disk path of the dotfile.
-    pub full_path: PathBuf,
-    /// The path of the dotfile relative to the users home directory.
-    pub relative_path: PathBuf,
+            // Print useful stacktrace in debug mode.
+            #[cfg(debug_assertions)]
+            {
+                let errors: Vec<_> = error.iter().map(ToString::to_string).collect();
+                eprintln!("error: {}", errors.join(" - "));
+
+                if let Some(backtrace) = error.backtrace() {
+                    eprintln!("{:?}", backtrace);
+                }
+
+                ::std::process::exit($exit_code);
+            }
+        }
+    };
+    ($error:expr) => {
+        fatal_error!(exit 1 => $error)
+    };
+
+    ($error:expr, $message:expr) => {
+        {
+            use $crate::ResultExt;
+
+            // We can only chain `Result`, not `Error`.
+            let error: Result<(), $crate::Error> = Err($error.into());
+            let error = error.chain_err(|| $message);
+            let error = error.err().unwrap();
+
+            fatal_error!(error);
+        }
+    };
 }
 
-use clap::{Arg, Command};
+/// Write a generic log message to standard error.
+macro_rules! log {
+    ($label:expr, $color:ident,
+     $logging_enabled:expr => $fmt:expr $(, $arg:expr )*) => {
+        {
+            use std::io;
+            use std::io::prelude::*;
 
-use std::path::PathBuf;
-use std::env;
+            if $logging_enabled {
+                use term;
 
-fn open_cache() -> Result<Cache, Error> {
-    let path = util::home_dir().join(".polk");
+                let mut t = term::stderr().unwrap();
 
-    Cache::at(path.to_owned())
+                t.fg(term::color::$color).ok();
+                write!(io::stderr(), "{}: ", $label).ok();
+                t.reset().ok();
+                writeln!(io::stderr(), $fmt $( , $arg )*).ok();
+            }
+        }
+    }
 }
 
-/// Gets the username of the current user.
-fn system_username() -> String {
-    match env::var("USER") {
-        Ok(username) => username,
-        Err(e) => fatal_error!(e, "could not get username"),
+mod tools;
+
+use polk::{Cache, UserCache, SourceSpec, Dotfile, DotfileKind, Environment, FeatureSet, Error, ErrorKind, ResultExt};
+use polk::{config, diff, doctor, feature, gc, journal, symlink};
+use polk::config::Config;
+use polk::log::{self, Level};
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+
+use std::path::PathBuf;
+use std::time::Duration;
+use std::io::prelude::*;
+use std::{env, io, process};
+
+/// Gets the environment, with the cache wherever `--cache-dir` says.
+fn environment(matches: &ArgMatches) -> Result<Environment, Error> {
+    let mut environment = Environment::current()?;
+
+    if let Some(cache_dir) = matches.get_one::<String>("cache-dir") {
+        environment.cache_path = PathBuf::from(cache_dir);
     }
+
+    // Both `--cache-dir` and `$POLK_HOME` are relative to where polk was run.
+    environment.cache_path = env::current_dir()?.join(&environment.cache_path);
+    Ok(environment)
+}
+
+/// Loads the configuration file given by `--config`, or the default one,
+/// then applies any environment variables over it.
+fn config(matches: &ArgMatches, environment: &Environment) -> Result<Config, Error> {
+    let mut config = match matches.get_one::<String>("config") {
+        Some(path) => Config::load(&env::current_dir()?.join(path))?,
+        None => Config::load_default(environment)?,
+    };
+
+    config.override_from_env(environment, &env::current_dir()?)?;
+    Ok(config)
 }
 
 mod arg {
@@ -64,8 +129,7 @@ mod arg {
 
     pub fn dotfile_source() -> Arg {
         Arg::new("SOURCE")
-            .help("Sets the source of the dotfiles")
-            .required(true)
+            .help("Sets the source of the dotfiles\nDefaults to the source in the configuration file")
             .index(1)
     }
 
@@ -76,11 +140,103 @@ mod arg {
             .value_name("USERNAME")
             .help("The username associated with the dotfiles\nDefaults to your computer username")
     }
+
+    pub fn target() -> Arg {
+        Arg::new("target")
+            .short('t')
+            .long("target")
+            .visible_alias("home")
+            .value_name("DIRECTORY")
+            .help("The directory to create symlinks in\nDefaults to your home directory")
+    }
+
+    pub fn link_mode() -> Arg {
+        Arg::new("link-mode")
+            .long("link-mode")
+            .value_name("MODE")
+            .value_parser(["symlink", "copy"])
+            .help("Whether to symlink or copy files\nDefaults to symlink")
+    }
+
+    pub fn conflicts() -> Arg {
+        Arg::new("conflicts")
+            .long("conflicts")
+            .value_name("POLICY")
+            .value_parser(["skip", "backup", "overwrite", "fail"])
+            .help("What to do about existing files in the way\nDefaults to skip")
+    }
+}
+
+/// Asks the user a yes or no question.
+fn confirm(question: &str) -> Result<bool, Error> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+
+    let answer = answer.trim().to_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
+
+/// Builds the symlink configuration for a subcommand.
+fn symlink_config(cmd_matches: &ArgMatches, environment: &Environment, config: &Config) -> Result<symlink::Config, Error> {
+    // Not every subcommand accepts every option.
+    let option = |name| cmd_matches.try_get_one::<String>(name).ok().flatten();
+
+    let home_path = match option("target") {
+        // Symlinks are recorded by absolute path.
+        Some(target) => env::current_dir()?.join(target),
+        None => match config.target {
+            Some(ref target) => environment.home_path.join(target),
+            None => environment.home_path.clone(),
+        },
+    };
+
+    let mut symlink_config = symlink::Config::with_home(home_path);
+
+    if let Some(mode) = option("link-mode").map(|mode| mode.parse()).transpose()?.or(config.link_mode) {
+        symlink_config.mode = mode;
+    }
+
+    if let Some(conflicts) = option("conflicts").map(|conflicts| conflicts.parse()).transpose()?.or(config.conflicts) {
+        symlink_config.conflicts = conflicts;
+    }
+
+    Ok(symlink_config)
+}
+
+/// Exit codes for `polk status`, which are combined when more than one applies.
+mod status_exit_code {
+    /// Some dotfiles are not deployed as they should be.
+    pub const DOTFILES_NEED_ATTENTION: i32 = 2;
+    /// The repository has uncommitted changes or has diverged from its upstream.
+    pub const REPOSITORY_NOT_CLEAN: i32 = 4;
 }
 
-fn polk() -> Result<(), Error> {
-    let cache = open_cache()?;
+/// Exit codes for `polk doctor`.
+mod doctor_exit_code {
+    /// Problems were found which are still there, either because they cannot
+    /// be fixed automatically or because `--fix` was not given.
+    pub const PROBLEMS_FOUND: i32 = 3;
+}
+
+/// Exit codes for errors which scripts may want to tell apart.
+///
+/// Any other error exits with 1.
+mod error_exit_code {
+    pub const NOT_GRABBED: i32 = 10;
+    pub const DIRTY_WORKTREE: i32 = 11;
+    pub const NO_REMOTE: i32 = 12;
+    pub const MANIFEST_PARSE: i32 = 13;
+    pub const UNSUPPORTED_HEAD: i32 = 14;
+    pub const LINK_CONFLICT: i32 = 15;
+    pub const LOCKED: i32 = 16;
+    pub const UNKNOWN_FEATURE: i32 = 17;
+}
 
+/// Runs polk, returning the exit code.
+fn polk() -> Result<i32, Error> {
     let matches = Command::new("Polk")
                           .version(env!("CARGO_PKG_VERSION"))
                           .author(env!("CARGO_PKG_AUTHORS"))
@@ -88,41 +244,209 @@ fn polk() -> Result<(), Error> {
                           .arg(Arg::new("verbose")
                                .short('v')
                                .long("verbose")
+                               .action(ArgAction::SetTrue)
                                .help("Enables verbose output"))
+                          .arg(Arg::new("cache-dir")
+                               .long("cache-dir")
+                               .value_name("DIRECTORY")
+                               .global(true)
+                               .help("The directory to keep dotfiles in\nDefaults to $POLK_HOME, or $XDG_DATA_HOME/polk"))
+                          .arg(Arg::new("config")
+                               .long("config")
+                               .value_name("FILE")
+                               .global(true)
+                               .help("The configuration file to read\nDefaults to $XDG_CONFIG_HOME/polk/config.toml"))
+                          .arg(Arg::new("feature")
+                               .long("feature")
+                               .value_name("FEATURE")
+                               .action(ArgAction::Append)
+                               .global(true)
+                               .help("Enables a feature this machine does not have, such as 'macos'"))
+                          .arg(Arg::new("run-hooks")
+                               .long("run-hooks")
+                               .action(ArgAction::SetTrue)
+                               .global(true)
+                               .help("Runs the hooks of your own dotfiles, which are never run for other users"))
+                          .arg(Arg::new("no-run-hooks")
+                               .long("no-run-hooks")
+                               .action(ArgAction::SetTrue)
+                               .global(true)
+                               .conflicts_with("run-hooks")
+                               .help("Does not run hooks, even if the configuration file says to"))
+                          .arg(Arg::new("no-wait")
+                               .long("no-wait")
+                               .action(ArgAction::SetTrue)
+                               .global(true)
+                               .help("Fails instead of waiting when another polk process is using the cache"))
                           .subcommand(Command::new("grab")
                                       .arg(arg::username())
                                       .arg(arg::dotfile_source())
+                                      .arg(Arg::new("ref")
+                                           .long("ref")
+                                           .value_name("REF")
+                                           .help("Checks out a branch, tag or commit and pins the dotfiles to it,\nso that `polk update` leaves them alone"))
                                       .about("Downloads dotfiles but does not create symlinks to them"))
                           .subcommand(Command::new("setup")
                                       .arg(arg::username())
+                                      .arg(arg::target())
+                                      .arg(arg::link_mode())
+                                      .arg(arg::conflicts())
                                       .arg(arg::dotfile_source())
                                       .about("Fetches dotfiles and creates symlinks to them"))
                           .subcommand(Command::new("update")
                                       .arg(arg::username())
+                                      .arg(Arg::new("unpin")
+                                           .long("unpin")
+                                           .action(ArgAction::SetTrue)
+                                           .help("Checks out the branch the dotfiles were grabbed from again before updating"))
                                       .about("Updates dotfiles via the internet"))
                           .subcommand(Command::new("link")
                                       .arg(arg::username())
+                                      .arg(arg::target())
+                                      .arg(arg::link_mode())
+                                      .arg(arg::conflicts())
                                       .about("Creates symbolic links to dotfiles"))
                           .subcommand(Command::new("unlink")
+                                      .arg(arg::username())
+                                      .arg(arg::target())
                                       .about("Deletes all symbolic links"))
                           .subcommand(Command::new("relink")
+                                      .arg(arg::username())
+                                      .arg(arg::target())
+                                      .arg(arg::link_mode())
+                                      .arg(arg::conflicts())
                                       .about("Recreates all symbolic links"))
+                          .subcommand(Command::new("encrypt")
+                                      .arg(arg::username())
+                                      .arg(arg::target())
+                                      .arg(Arg::new("FILE")
+                                           .required(true)
+                                           .help("The file to encrypt into the dotfiles repository"))
+                                      .about("Encrypts a file into the dotfiles repository"))
                           .subcommand(Command::new("shell")
                                       .arg(arg::username())
                                       .about("Open up a shell with a temporary $HOME and the given users dotfiles"))
                           .subcommand(Command::new("forget")
                                       .about("Deletes all symbolic links and cached dotfiles files"))
+                          .subcommand(Command::new("readopt")
+                                      .arg(arg::username())
+                                      .arg(arg::target())
+                                      .arg(Arg::new("yes")
+                                           .short('y')
+                                           .long("yes")
+                                           .action(ArgAction::SetTrue)
+                                           .help("Readopts files without asking first"))
+                                      .arg(Arg::new("PATHS")
+                                           .num_args(0..)
+                                           .help("Only readopt files inside these paths"))
+                                      .about("Copies files that replaced symbolic links back into the dotfiles and restores the links"))
+                          .subcommand(Command::new("status")
+                                      .arg(arg::username())
+                                      .arg(arg::target())
+                                      .arg(Arg::new("format")
+                                           .long("format")
+                                           .value_name("FORMAT")
+                                           .value_parser(["text", "json"])
+                                           .help("How to print the report\nDefaults to text"))
+                                      .about("Shows the state of every dotfile and of the dotfiles repository")
+                                      .after_help("Exits with 2 if any dotfiles need attention, 4 if the repository \
+                                                   has uncommitted changes or has diverged from its upstream, \
+                                                   or 6 if both."))
+                          .subcommand(Command::new("diff")
+                                      .arg(arg::username())
+                                      .arg(arg::target())
+                                      .arg(Arg::new("PATHS")
+                                           .num_args(0..)
+                                           .help("Only compare dotfiles inside these paths"))
+                                      .about("Shows how linking would change the files in the home directory"))
+                          .subcommand(Command::new("doctor")
+                                      .arg(Arg::new("fix")
+                                           .long("fix")
+                                           .action(ArgAction::SetTrue)
+                                           .help("Repairs the problems that are safe to repair"))
+                                      .about("Checks the cache, dotfiles and links for problems")
+                                      .after_help("Exits with 3 if any problems are left."))
+                          .subcommand(Command::new("gc")
+                                      .arg(Arg::new("older-than")
+                                           .long("older-than")
+                                           .value_name("DAYS")
+                                           .value_parser(value_parser!(u64))
+                                           .default_value("7")
+                                           .help("Only deletes backups older than this many days"))
+                                      .about("Deletes leftover backups and stale caches, and compacts dotfiles repositories"))
+                          .subcommand(Command::new("migrate")
+                                      .about("Moves the cache from ~/.polk to the cache directory and updates every link into it"))
                           .subcommand(Command::new("info")
+                                      .arg(arg::username())
+                                      .arg(arg::target())
                                       .about("List information"))
                           .get_matches();
 
-    let verbose = matches.contains_id("verbose");
+    let verbose = matches.get_flag("verbose");
+    let wait = !matches.get_flag("no-wait");
     let mut term = term::stdout().expect("could not open stdout for term library");
 
+    let mut environment = self::environment(&matches)?;
+    let config = self::config(&matches, &environment)?;
+
+    if let Some(ref user) = config.user {
+        environment.username = user.clone();
+    }
+
+    environment.run_hooks = if matches.get_flag("run-hooks") {
+        true
+    } else if matches.get_flag("no-run-hooks") {
+        false
+    } else {
+        config.run_hooks.unwrap_or(false)
+    };
+
+    environment.features = match matches.get_many::<String>("feature") {
+        Some(features) => features.cloned().collect(),
+        None => config.features.clone().unwrap_or_default(),
+    };
+
+    let legacy_cache_path = environment.legacy_cache_path();
+    // An explicit `--cache-dir` or `POLK_HOME` is always used as given.
+    let is_unmigrated = environment.cache_path == environment.default_cache_path() &&
+        legacy_cache_path.is_dir() && !environment.cache_path.exists();
+
+    if let Some(("migrate", _)) = matches.subcommand() {
+        if !is_unmigrated {
+            ilog!("there is no cache at '{}' to migrate", legacy_cache_path.display());
+            return Ok(0);
+        }
+
+        let cache_path = environment.cache_path.clone();
+        environment.cache_path = legacy_cache_path;
+
+        let cache = Cache::open(environment)?;
+        let _lock = cache.lock(wait)?;
+
+        cache.migrate(&cache_path, verbose)?;
+        ilog!("moved the cache to '{}'", cache_path.display());
+        return Ok(0);
+    }
+
+    if is_unmigrated {
+        // Keep using the old cache until it is migrated.
+        warn!("using the cache at '{}', run `polk migrate` to move it to '{}'",
+              legacy_cache_path.display(), environment.cache_path.display());
+        environment.cache_path = legacy_cache_path;
+    }
+
+    let cache = Cache::at(environment)?;
+
     let username = if let Some(cmd_matches) = matches.subcommand().map(|s| s.1) {
-        cmd_matches.get_one::<String>("user").map(ToOwned::to_owned).unwrap_or_else(|| system_username())
+        // Not every subcommand accepts a username.
+        cmd_matches.try_get_one::<String>("user").ok().flatten().cloned().unwrap_or_else(|| cache.environment.username.clone())
     } else {
-        system_username()
+        cache.environment.username.clone()
+    };
+
+    let symlink_config = match matches.subcommand() {
+        Some((_, cmd_matches)) => symlink_config(cmd_matches, &cache.environment, &config)?,
+        None => symlink::Config::new(&cache.environment),
     };
 
     match matches.subcommand() {
@@ -133,78 +457,311 @@ fn polk() -> Result<(), Error> {
         Some(("setup", cmd_matches)) => {
             ilog!("setting up");
             let mut user_cache = cache.user(username);
+            let _lock = user_cache.lock(wait)?;
 
             let subcommand = matches.subcommand().map(|s| s.0);
 
-            // Gets a value for config if supplied by user, or defaults to "default.conf"
-            let source_str = cmd_matches.get_one::<String>("SOURCE").unwrap();
+            let source_str = match cmd_matches.get_one::<String>("SOURCE").or(config.source.as_ref()) {
+                Some(source_str) => source_str,
+                None => return Err("no source given, and there is no default source in the configuration file".into()),
+            };
             let source_spec: SourceSpec = source_str.parse()?;
 
             vlog!(verbose => "Getting dotfiles from {}", source_spec.description());
 
             match subcommand {
-                Some("grab") => user_cache.grab(&source_spec, verbose)?,
-                Some("setup") => user_cache.setup(&source_spec, verbose)?,
+                Some("grab") => {
+                    user_cache.grab(&source_spec, verbose)?;
+
+                    if let Some(reference) = cmd_matches.get_one::<String>("ref") {
+                        user_cache.pin(reference, verbose)?;
+                    }
+                },
+                Some("setup") => user_cache.setup_ext(&source_spec, &symlink_config, wait, verbose)?,
                 _ => unreachable!(),
             }
         },
-        Some(("update", _)) => {
+        Some(("update", cmd_matches)) => {
             let mut user_cache = cache.user(username);
-            user_cache.update(verbose)?;
+            let _lock = user_cache.lock(wait)?;
+
+            if cmd_matches.get_flag("unpin") {
+                user_cache.unpin(verbose)?;
+            }
+
+            user_cache.update(wait, verbose)?;
         },
         Some(("link", _)) => {
             let mut user_cache = cache.user(username);
-            user_cache.link(verbose)?;
+            let _lock = user_cache.lock(wait)?;
+            user_cache.link_ext(&symlink_config, wait, verbose)?;
         },
         Some(("unlink", _)) => {
             let mut user_cache = cache.user(username);
-            user_cache.unlink(verbose)?;
+            let _lock = user_cache.lock(wait)?;
+            user_cache.unlink_ext(&symlink_config, wait, verbose)?;
         },
         Some(("relink", _)) => {
             let mut user_cache = cache.user(username);
-            user_cache.unlink(verbose)?;
-            user_cache.link(verbose)?;
+            let _lock = user_cache.lock(wait)?;
+            user_cache.relink(&symlink_config, wait, verbose)?;
+        },
+        Some(("encrypt", cmd_matches)) => {
+            let mut user_cache = cache.user(username);
+            let _lock = user_cache.lock(wait)?;
+            let path = env::current_dir()?.join(cmd_matches.get_one::<String>("FILE").unwrap());
+
+            let encrypted_path = user_cache.encrypt(&path, &symlink_config, wait, verbose)?;
+            ilog!("encrypted into '{}' and staged it, remember to commit it", encrypted_path.display());
         },
         Some(("shell", _)) => {
             let mut user_cache = cache.user(username);
-            let config = tools::shell::Config::default();
+            // Released when the shell replaces polk.
+            let _lock = user_cache.lock(wait)?;
+            let config = tools::shell::Config::new(&cache.environment);
 
-            let shell = tools::shell::Shell::create(&mut user_cache, config)?;
+            let shell = tools::shell::Shell::create(&mut user_cache, config, wait)?;
             shell.exec()?;
         },
         Some(("forget", _)) => {
+            let _lock = cache.lock(wait)?;
             cache.forget(verbose)?;
         },
         Some(("info", _)) => {
             let user_cache = cache.user(username);
-            let features = feature::FeatureSet::current_system();
+            let features = feature::FeatureSet::for_environment(&cache.environment)?;
 
             info::print_features(&features)?;
-            info::print_configuration(&user_cache)?;
-            info::print_dotfiles(user_cache.dotfiles()?, &mut *term)?;
+            info::print_configuration(&user_cache, &symlink_config)?;
+            info::print_dotfiles(user_cache.dotfiles()?, &symlink_config, &mut *term)?;
+        },
+        Some(("diff", cmd_matches)) => {
+            let user_cache = cache.user(username);
+            let current_dir = env::current_dir()?;
+            let paths: Vec<_> = cmd_matches.get_many::<String>("PATHS").into_iter().flatten().
+                map(|path| current_dir.join(path)).
+                collect();
+
+            for change in user_cache.changes(&symlink_config, &paths)? {
+                print!("{}", change.unified());
+            }
+        },
+        Some(("readopt", cmd_matches)) => {
+            let user_cache = cache.user(username);
+            let _lock = user_cache.lock(wait)?;
+            let current_dir = env::current_dir()?;
+            let paths: Vec<_> = cmd_matches.get_many::<String>("PATHS").into_iter().flatten().
+                map(|path| current_dir.join(path)).
+                collect();
+
+            let replaced: Vec<_> = user_cache.replaced_links(&symlink_config)?.into_iter().filter(|entry| {
+                paths.is_empty() || paths.iter().any(|p| entry.path.starts_with(p) || entry.target.starts_with(p))
+            }).collect();
+
+            if replaced.is_empty() {
+                ilog!("no symbolic links have been replaced");
+            }
+
+            for entry in replaced {
+                // Show what readopting would change in the repository.
+                let change = diff::Change {
+                    old_path: entry.target.clone(),
+                    new_path: entry.path.clone(),
+                    old: diff::read(&entry.target)?,
+                    new: diff::read(&entry.path)?,
+                    hidden: false,
+                };
+
+                if change.is_change() {
+                    print!("{}", change.unified());
+
+                    let question = format!("copy '{}' into the dotfiles and relink it?", entry.path.display());
+                    if !cmd_matches.get_flag("yes") && !confirm(&question)? {
+                        continue;
+                    }
+                }
+
+                user_cache.readopt(&entry, wait, verbose)?;
+                ilog!("readopted '{}', remember to commit '{}'", entry.path.display(), entry.target.display());
+            }
+        },
+        Some(("doctor", cmd_matches)) => {
+            // Nothing else may change the cache between diagnosing and fixing it.
+            let _lock = if cmd_matches.get_flag("fix") { Some(cache.lock(wait)?) } else { None };
+            let mut problems = doctor::diagnose(&cache)?;
+
+            if cmd_matches.get_flag("fix") && problems.iter().any(|p| p.fix.is_some()) {
+                doctor::fix(&cache, &problems, wait, verbose)?;
+                problems.retain(|p| p.fix.is_none());
+            }
+
+            for problem in problems.iter() {
+                match problem.fix {
+                    Some(ref fix) => { warn!("{} (`--fix` will {})", problem.description, fix.description()); },
+                    None => { warn!("{}", problem.description); },
+                }
+            }
+
+            if problems.is_empty() {
+                ilog!("no problems found");
+            } else {
+                return Ok(doctor_exit_code::PROBLEMS_FOUND);
+            }
+        },
+        Some(("gc", cmd_matches)) => {
+            let _lock = cache.lock(wait)?;
+            let days = *cmd_matches.get_one::<u64>("older-than").unwrap();
+            let reclaimed = gc::collect(&cache, Duration::from_secs(days * 24 * 60 * 60), verbose)?;
+
+            ilog!("reclaimed {}", gc::format_size(reclaimed));
+        },
+        Some(("status", cmd_matches)) => {
+            let user_cache = cache.user(username);
+
+            if !user_cache.is_grabbed() {
+                return Err(ErrorKind::NotGrabbed(user_cache.username.clone()).into());
+            }
+
+            let format = match cmd_matches.get_one::<String>("format") {
+                Some(format) => format.parse()?,
+                None => config.format.unwrap_or(config::Format::Text),
+            };
+
+            let dotfiles = user_cache.dotfiles()?;
+            let statuses = user_cache.status(&dotfiles, &symlink_config)?;
+            let violations = user_cache.mode_violations(&dotfiles, &symlink_config)?;
+            let repository_status = user_cache.repository_status()?;
+
+            match format {
+                config::Format::Text => {
+                    info::print_status(&statuses, &mut *term)?;
+
+                    for violation in violations.iter() {
+                        warn!("'{}' has mode {:o} but should be no more permissive than {:o}",
+                              violation.path.display(), violation.actual, violation.expected);
+                    }
+
+                    info::print_repository_status(&repository_status, &mut *term)?;
+                },
+                config::Format::Json => info::print_status_json(&statuses, &violations, &repository_status),
+            }
+
+            let mut exit_code = 0;
+
+            if !statuses.iter().all(|s| s.state.is_ok()) || !violations.is_empty() {
+                exit_code |= status_exit_code::DOTFILES_NEED_ATTENTION;
+            }
+
+            if !repository_status.is_clean() {
+                exit_code |= status_exit_code::REPOSITORY_NOT_CLEAN;
+            }
+
+            return Ok(exit_code);
         },
         _ => unreachable!(),
     }
 
-    Ok(())
+    Ok(0)
+}
+
+/// The exit code for a process killed by SIGINT.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// Writes polk's log messages to the terminal.
+struct TerminalSink;
+
+impl log::Sink for TerminalSink {
+    fn log(&self, level: Level, message: &str) {
+        match level {
+            Level::Verbose => { log!("[log]", YELLOW, true => "{}", message); },
+            Level::Info => { log!("[info]", BRIGHT_BLUE, true => "{}", message); },
+            Level::Warning => { log!("warning", MAGENTA, true => "{}", message); },
+            Level::Progress => println!("{}", message),
+        }
+    }
+}
+
+/// Makes Ctrl-C roll back whatever polk is in the middle of rather than killing it.
+///
+/// When nothing needs rolling back, Ctrl-C exits immediately as usual.
+fn install_interrupt_handler() {
+    let result = ctrlc::set_handler(|| {
+        if !journal::interrupt() {
+            process::exit(INTERRUPTED_EXIT_CODE);
+        }
+    });
+
+    if let Err(e) = result {
+        warn!("could not install interrupt handler: {}", e);
+    }
 }
 
 fn main() {
+    log::set_sink(TerminalSink);
+    self::install_interrupt_handler();
+
     match polk() {
-        Ok(..) => (),
+        Ok(0) => (),
+        Ok(exit_code) => process::exit(exit_code),
         Err(e) => {
-            fatal_error!(e);
+            let exit_code = self::exit_code(e.specific_kind());
+
+            if let Some(hint) = self::hint(e.specific_kind()) {
+                ilog!("{}", hint);
+            }
+
+            fatal_error!(exit exit_code => e);
         },
     }
 }
 
+/// The exit code for an error.
+fn exit_code(kind: &ErrorKind) -> i32 {
+    match *kind {
+        ErrorKind::NotGrabbed(..) => error_exit_code::NOT_GRABBED,
+        ErrorKind::DirtyWorktree(..) => error_exit_code::DIRTY_WORKTREE,
+        ErrorKind::NoRemote(..) => error_exit_code::NO_REMOTE,
+        ErrorKind::ManifestParse(..) => error_exit_code::MANIFEST_PARSE,
+        ErrorKind::UnsupportedHead(..) => error_exit_code::UNSUPPORTED_HEAD,
+        ErrorKind::LinkConflict(..) => error_exit_code::LINK_CONFLICT,
+        ErrorKind::Locked(..) => error_exit_code::LOCKED,
+        ErrorKind::UnknownFeature(..) => error_exit_code::UNKNOWN_FEATURE,
+        _ => 1,
+    }
+}
+
+/// Suggests how to fix an error, if there is an obvious way to.
+fn hint(kind: &ErrorKind) -> Option<String> {
+    match *kind {
+        ErrorKind::NotGrabbed(ref username) => {
+            Some(format!("run `polk grab --user {} <SOURCE>` to download their dotfiles", username))
+        },
+        ErrorKind::DirtyWorktree(ref path) => {
+            Some(format!("commit or stash the changes in '{}' and try again", path.display()))
+        },
+        ErrorKind::NoRemote(ref path) => {
+            Some(format!("add a remote to '{}' with `git remote add origin <URL>`", path.display()))
+        },
+        ErrorKind::UnsupportedHead(..) => Some("check out a branch in the dotfiles repository".to_owned()),
+        ErrorKind::ManifestParse(..) => Some("run `polk doctor` to check the cache for problems".to_owned()),
+        ErrorKind::LinkConflict(ref path, _) => Some(format!("move '{}' out of the way and try again", path.display())),
+        ErrorKind::Locked(..) => Some("wait for the other polk process to finish, or leave out `--no-wait`".to_owned()),
+        _ => None,
+    }
+}
+
 mod info {
-    use {Dotfile, FeatureSet, UserCache, Error};
+    use {Dotfile, DotfileKind, FeatureSet, UserCache, Error};
     use {symlink, feature};
+    use polk::backend::RepositoryStatus;
+    use polk::status::{DotfileStatus, State};
+    use polk::permissions::Violation;
 
     use term::StdoutTerminal;
     use term;
 
+    use std::time::{SystemTime, UNIX_EPOCH};
+
     pub fn print_features(features: &FeatureSet) -> Result<(), Error> {
         let mut enabled_features: Vec<_> = features.enabled_features.iter().cloned().collect();
         let mut disabled_features: Vec<_> = features.disabled();
@@ -225,25 +782,58 @@ mod info {
         Ok(())
     }
 
-    pub fn print_configuration(user_cache: &UserCache) -> Result<(), Error> {
+    pub fn print_configuration(user_cache: &UserCache,
+                               symlink_config: &symlink::Config) -> Result<(), Error> {
         println!("Configuration\n-------------");
         println!("  user cache: {}", user_cache.base_path().display());
+        println!("  target: {}", symlink_config.home_path.display());
+
+        if user_cache.is_grabbed() {
+            let manifest = user_cache.manifest()?;
+
+            println!("  source: {}", manifest.source);
+            if let Some(ref pinned_ref) = manifest.pinned_ref {
+                println!("  pinned to: {}", pinned_ref);
+            }
+            if let Some(last_updated) = manifest.last_updated {
+                println!("  last updated: {}", self::format_age(last_updated));
+            }
+            if let (Some(ref revision), Some(ref target)) = (&manifest.last_linked_revision, &manifest.target) {
+                println!("  last linked: {} into {}", &revision[..revision.len().min(7)], target.display());
+            }
+        }
         println!();
 
         Ok(())
     }
 
-    pub fn print_dotfiles<I>(dotfiles: I, term: &mut StdoutTerminal) -> Result<(), Error>
+    /// Describes how long ago a Unix timestamp was, such as `3 hours ago`.
+    fn format_age(timestamp: u64) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let seconds = now.saturating_sub(timestamp);
+
+        let (count, unit) = match seconds {
+            0..=59 => return "just now".to_owned(),
+            60..=3599 => (seconds / 60, "minute"),
+            3600..=86399 => (seconds / 3600, "hour"),
+            _ => (seconds / 86400, "day"),
+        };
+
+        format!("{} {}{} ago", count, unit, if count == 1 { "" } else { "s" })
+    }
+
+    pub fn print_dotfiles<I>(dotfiles: I,
+                             symlink_config: &symlink::Config,
+                             term: &mut StdoutTerminal) -> Result<(), Error>
         where I: IntoIterator<Item=Dotfile> {
         println!("Dotfiles\n--------");
 
         let mut dotfiles: Vec<_> = dotfiles.into_iter().collect();
         dotfiles.sort_by_key(|d| d.relative_path.clone());
-        let symlink_config = symlink::Config::default();
 
         for dotfile in dotfiles {
-            let symlink_path = symlink::path(&dotfile, &symlink_config);
-            let symlink_exists = symlink::exists(&dotfile, &symlink_config)?;
+            let symlink_path = symlink::path(&dotfile, symlink_config);
+            let symlink_exists = symlink::exists(&dotfile, symlink_config)?;
             let required_features: Vec<_> = feature::required_features(&dotfile).into_iter().collect();
 
             let bullet = if symlink_exists {
@@ -260,12 +850,27 @@ mod info {
 
             print!("{}", dotfile.full_path.display());
 
-            if symlink_exists {
-                term.fg(term::color::GREEN)?;
-                print!(" -> {}", symlink_path.display());
+            if dotfile.kind == DotfileKind::Directory {
+                print!("/");
+            }
+
+            let kind_label = match dotfile.kind {
+                DotfileKind::Template => Some("template"),
+                DotfileKind::Secret => Some("secret"),
+                DotfileKind::File | DotfileKind::Directory => None,
+            };
+
+            if let Some(label) = kind_label {
+                term.fg(term::color::CYAN)?;
+                print!(" ({})", label);
                 term.reset()?;
             }
 
+            // Always show where the dotfile maps to, dimmed if it isn't linked yet.
+            term.fg(if symlink_exists { term::color::GREEN } else { term::color::BRIGHT_BLACK })?;
+            print!(" -> {}", symlink_path.display());
+            term.reset()?;
+
             if !required_features.is_empty() {
                 term.fg(term::color::YELLOW)?;
                 print!(" requires: [{}]", required_features.join(", "));
@@ -277,5 +882,122 @@ mod info {
 
         Ok(())
     }
-}
 
+    /// Prints the state of every dotfile.
+    pub fn print_status(statuses: &[DotfileStatus],
+                        term: &mut StdoutTerminal) -> Result<(), Error> {
+        println!("Dotfiles\n--------");
+
+        let mut statuses: Vec<_> = statuses.iter().collect();
+        statuses.sort_by_key(|s| s.destination.clone());
+
+        for status in statuses.iter() {
+            let color = match status.state {
+                State::Linked => term::color::GREEN,
+                State::Excluded => term::color::BRIGHT_BLACK,
+                State::Elsewhere(..) | State::Drifted => term::color::YELLOW,
+                State::Missing | State::Blocked { .. } | State::Replaced | State::Dangling(..) => term::color::RED,
+            };
+
+            term.fg(color)?;
+            print!("  {:<10}", status.state.label());
+            term.reset()?;
+
+            print!(" {}", status.destination.display());
+
+            term.fg(term::color::BRIGHT_BLACK)?;
+            match status.state {
+                State::Elsewhere(ref target) | State::Dangling(ref target) => print!(" -> {}", target.display()),
+                State::Blocked { directory: true } => print!(" (a directory is in the way)"),
+                State::Blocked { directory: false } => print!(" (a file is in the way)"),
+                State::Drifted => print!(" (modified since it was written)"),
+                State::Replaced => print!(" (replaced by a file, see `polk readopt`)"),
+                State::Linked | State::Missing | State::Excluded => (),
+            }
+            term.reset()?;
+
+            println!();
+        }
+        println!();
+
+        Ok(())
+    }
+
+    /// Prints the state of the dotfiles repository.
+    pub fn print_repository_status(status: &RepositoryStatus,
+                                   term: &mut StdoutTerminal) -> Result<(), Error> {
+        println!("Repository\n----------");
+
+        let revision = status.revision.as_ref().map(|r| &r[..r.len().min(7)]).unwrap_or("none");
+        match status.branch {
+            Some(ref branch) => println!("  revision: {} ({})", revision, branch),
+            None => println!("  revision: {}", revision),
+        }
+
+        match status.ahead_behind {
+            Some((0, 0)) => println!("  upstream: up to date"),
+            Some((ahead, behind)) => {
+                term.fg(term::color::YELLOW)?;
+                println!("  upstream: {} ahead, {} behind", ahead, behind);
+                term.reset()?;
+            },
+            None => println!("  upstream: none"),
+        }
+
+        if !status.dirty.is_empty() {
+            term.fg(term::color::YELLOW)?;
+            println!("  uncommitted changes:");
+            term.reset()?;
+
+            for path in status.dirty.iter() {
+                println!("    {}", path.display());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Prints the state of every dotfile and of the dotfiles repository as JSON.
+    pub fn print_status_json(statuses: &[DotfileStatus],
+                             violations: &[Violation],
+                             repository_status: &RepositoryStatus) {
+        let mut statuses: Vec<_> = statuses.iter().collect();
+        statuses.sort_by_key(|s| s.destination.clone());
+
+        let dotfiles: Vec<_> = statuses.iter().map(|status| {
+            let target = match status.state {
+                State::Elsewhere(ref target) | State::Dangling(ref target) => Some(target.display().to_string()),
+                _ => None,
+            };
+
+            json!({
+                "source": status.source.display().to_string(),
+                "destination": status.destination.display().to_string(),
+                "state": status.state.label(),
+                "target": target,
+            })
+        }).collect();
+
+        let violations: Vec<_> = violations.iter().map(|violation| json!({
+            "path": violation.path.display().to_string(),
+            "mode": format!("{:o}", violation.actual),
+            "expected": format!("{:o}", violation.expected),
+        })).collect();
+
+        let dirty: Vec<_> = repository_status.dirty.iter().map(|path| path.display().to_string()).collect();
+
+        let report = json!({
+            "dotfiles": dotfiles,
+            "mode_violations": violations,
+            "repository": {
+                "revision": repository_status.revision,
+                "branch": repository_status.branch,
+                "ahead": repository_status.ahead_behind.map(|(ahead, _)| ahead),
+                "behind": repository_status.ahead_behind.map(|(_, behind)| behind),
+                "dirty": dirty,
+            },
+        });
+
+        println!("{:#}", report);
+    }
+}
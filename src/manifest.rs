@@ -0,0 +1,124 @@
+//! The manifest that records what polk knows about a user cache.
+
+use {SourceSpec, Error, ErrorKind, ResultExt};
+use symlink;
+
+use toml;
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::fs;
+
+/// The version of the manifest format written by this version of polk.
+pub const VERSION: i64 = 2;
+
+/// A manifest file for a user cache.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserManifest {
+    /// The version of the manifest format.
+    pub version: i64,
+    /// The source of the dotfiles.
+    pub source: SourceSpec,
+    /// The branch, tag or commit that the dotfiles are pinned to.
+    ///
+    /// Pinned dotfiles are never updated.
+    pub pinned_ref: Option<String>,
+    /// When the dotfiles were last grabbed or updated, in seconds since the Unix epoch.
+    pub last_updated: Option<u64>,
+    /// The revision of the dotfiles when they were last linked.
+    pub last_linked_revision: Option<String>,
+    /// The features that were enabled when the dotfiles were last linked.
+    #[serde(default)]
+    pub linked_features: Vec<String>,
+    /// How files were deployed when the dotfiles were last linked.
+    pub link_mode: Option<symlink::Mode>,
+    /// Where the dotfiles were last linked.
+    pub target: Option<PathBuf>,
+}
+
+impl UserManifest {
+    /// Creates a manifest for newly grabbed dotfiles.
+    pub fn new(source: SourceSpec) -> Self {
+        UserManifest {
+            version: VERSION,
+            source,
+            pinned_ref: None,
+            last_updated: Some(self::now()),
+            last_linked_revision: None,
+            linked_features: Vec::new(),
+            link_mode: None,
+            target: None,
+        }
+    }
+
+    /// Loads the manifest from disk, upgrading it if an older version of polk wrote it.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let manifest_toml = fs::read_to_string(path)?;
+        let manifest = toml::from_str(&manifest_toml).chain_err(|| ErrorKind::ManifestParse(path.to_owned()))?;
+        let manifest = self::migrate(manifest).chain_err(|| ErrorKind::ManifestParse(path.to_owned()))?;
+
+        manifest.try_into().chain_err(|| ErrorKind::ManifestParse(path.to_owned()))
+    }
+
+    /// Saves the manifest to disk.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let manifest_toml = toml::to_string(self).chain_err(|| "failed to create manifest toml")?;
+
+        fs::write(path, manifest_toml)?;
+        Ok(())
+    }
+
+    /// Records that the dotfiles were just updated.
+    pub fn touch(&mut self) {
+        self.last_updated = Some(self::now());
+    }
+}
+
+/// Upgrades a manifest written by an older version of polk to the current format.
+fn migrate(mut manifest: toml::Value) -> Result<toml::Value, Error> {
+    let table = manifest.as_table_mut().ok_or("the manifest is not a table")?;
+
+    // The first manifests did not have a version.
+    let version = match table.get("version") {
+        Some(version) => version.as_integer().ok_or("the manifest version is not a number")?,
+        None => 1,
+    };
+
+    if version > VERSION {
+        bail!("the manifest has version {}, but this version of polk only understands up to {}", version, VERSION);
+    }
+
+    // Version 1 only had the source, and everything since is optional.
+    table.insert("version".to_owned(), toml::Value::Integer(VERSION));
+    Ok(manifest)
+}
+
+/// Gets the current time in seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(manifest_toml: &str) -> Result<UserManifest, Error> {
+        let manifest = self::migrate(toml::from_str(manifest_toml).unwrap())?;
+        Ok(manifest.try_into().unwrap())
+    }
+
+    #[test]
+    fn version_one_manifests_are_upgraded() {
+        let manifest = parse("source = \"github:jenny\"").unwrap();
+
+        assert_eq!(manifest.version, VERSION);
+        assert_eq!(manifest.source, "github:jenny".parse().unwrap());
+        assert_eq!(manifest.last_linked_revision, None);
+        assert!(manifest.linked_features.is_empty());
+    }
+
+    #[test]
+    fn newer_manifests_are_rejected() {
+        assert!(parse("version = 3\nsource = \"github:jenny\"").is_err());
+    }
+}
@@ -0,0 +1,284 @@
+use {Environment, Error};
+
+use std::path::{Component, Path, PathBuf};
+
+/// The prefix which is replaced with `.` when dot prefixes are enabled.
+pub const DOT_PREFIX: &str = "dot-";
+
+/// Directories that XDG variables fall back to when unset,
+/// relative to the home directory.
+const XDG_DEFAULTS: &[(&str, &str)] = &[
+    ("XDG_CONFIG_HOME", ".config"),
+    ("XDG_DATA_HOME", ".local/share"),
+    ("XDG_STATE_HOME", ".local/state"),
+    ("XDG_CACHE_HOME", ".cache"),
+    ("XDG_BIN_HOME", ".local/bin"),
+];
+
+/// A rule that maps a path in the repository to somewhere else.
+///
+/// `from` is a path in the repository. It may end with `/**` to map
+/// everything beneath it (the default), or `/*` to only map its direct children.
+///
+/// `to` is the destination, relative to the home directory. It may start with
+/// `~`, `$HOME` or an XDG base directory variable such as `$XDG_CONFIG_HOME`,
+/// but must not lead outside of the home directory.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rule {
+    pub from: String,
+    pub to: String,
+}
+
+/// Maps paths in the repository to paths relative to the home directory.
+#[derive(Clone, Debug, Default)]
+pub struct Mapper {
+    rules: Vec<CompiledRule>,
+    dot_prefix: bool,
+}
+
+/// A rule with its paths resolved.
+#[derive(Clone, Debug)]
+struct CompiledRule {
+    from: PathBuf,
+    recursive: bool,
+    to: PathBuf,
+}
+
+impl Mapper {
+    /// Creates a new mapper, resolving variables from the environment.
+    pub fn new(rules: &[Rule], dot_prefix: bool, environment: &Environment) -> Result<Self, Error> {
+        Mapper::with_vars(rules, dot_prefix, &environment.home_path, |name| {
+            // The XDG specification says relative paths should be ignored.
+            let value = environment.var(name).ok()??;
+            if Path::new(value).is_absolute() { Some(value.to_owned()) } else { None }
+        })
+    }
+
+    /// Creates a new mapper, resolving variables with a custom lookup function.
+    pub fn with_vars<F>(rules: &[Rule], dot_prefix: bool, home: &Path, var: F) -> Result<Self, Error>
+        where F: Fn(&str) -> Option<String> {
+        let rules = rules.iter().map(|rule| {
+            let (from, recursive) = if let Some(from) = rule.from.strip_suffix("/**") {
+                (from, true)
+            } else if let Some(from) = rule.from.strip_suffix("/*") {
+                (from, false)
+            } else {
+                (rule.from.as_str(), true)
+            };
+
+            Ok(CompiledRule {
+                from: PathBuf::from(from.trim_end_matches('/')),
+                recursive,
+                to: self::expand_destination(&rule.to, home, &var)?,
+            })
+        }).collect::<Result<_, Error>>()?;
+
+        Ok(Mapper { rules, dot_prefix })
+    }
+
+    /// Maps a path relative to the repository root to a path relative to the home directory.
+    ///
+    /// The first matching rule wins.
+    pub fn map(&self, repository_path: &Path) -> PathBuf {
+        for rule in self.rules.iter() {
+            if let Ok(rest) = repository_path.strip_prefix(&rule.from) {
+                if !rule.recursive && rest.components().count() > 1 {
+                    continue;
+                }
+
+                if rest.as_os_str().is_empty() {
+                    return rule.to.clone();
+                }
+
+                return rule.to.join(self.rename(rest));
+            }
+        }
+
+        self.rename(repository_path)
+    }
+
+    /// Works out which path relative to the repository root maps to a path
+    /// relative to the home directory.
+    ///
+    /// Returns `None` if no path in the repository would end up there.
+    pub fn unmap(&self, path: &Path) -> Option<PathBuf> {
+        let from_rules = self.rules.iter().filter_map(|rule| {
+            let rest = path.strip_prefix(&rule.to).ok()?;
+
+            if rest.as_os_str().is_empty() {
+                Some(rule.from.clone())
+            } else {
+                Some(rule.from.join(self.unrename(rest)))
+            }
+        });
+
+        // An earlier rule may claim the path before the one it came from.
+        from_rules.chain(Some(self.unrename(path))).find(|candidate| self.map(candidate) == path)
+    }
+
+    /// Replaces the dot prefix on every component of a path, if enabled.
+    fn rename(&self, path: &Path) -> PathBuf {
+        if !self.dot_prefix {
+            return path.to_owned();
+        }
+
+        path.components().map(|component| match component {
+            Component::Normal(name) => match name.to_str() {
+                Some(name) if name.starts_with(DOT_PREFIX) => format!(".{}", &name[DOT_PREFIX.len()..]).into(),
+                _ => name.to_owned(),
+            },
+            other => other.as_os_str().to_owned(),
+        }).collect()
+    }
+
+    /// Adds the dot prefix to every hidden component of a path, if enabled.
+    fn unrename(&self, path: &Path) -> PathBuf {
+        if !self.dot_prefix {
+            return path.to_owned();
+        }
+
+        path.components().map(|component| match component {
+            Component::Normal(name) => match name.to_str() {
+                Some(name) if name.starts_with('.') => format!("{}{}", DOT_PREFIX, &name[1..]).into(),
+                _ => name.to_owned(),
+            },
+            other => other.as_os_str().to_owned(),
+        }).collect()
+    }
+}
+
+/// Expands the destination of a rule into a path relative to the home directory.
+fn expand_destination<F>(to: &str, home: &Path, var: &F) -> Result<PathBuf, Error>
+    where F: Fn(&str) -> Option<String> {
+    let (head, tail) = match to.find('/') {
+        Some(index) => (&to[..index], to[index + 1..].trim_start_matches('/')),
+        None => (to, ""),
+    };
+
+    let base = if head == "~" || head == "$HOME" {
+        PathBuf::new()
+    } else if let Some(name) = head.strip_prefix('$') {
+        let default = match XDG_DEFAULTS.iter().find(|&&(n, _)| n == name) {
+            Some(&(_, default)) => default,
+            None => bail!("unknown variable '{}' in path mapping '{}'", head, to),
+        };
+
+        match var(name) {
+            // Paths are kept relative so that they follow the symlink target.
+            Some(ref value) if !value.is_empty() => match Path::new(value).strip_prefix(home) {
+                Ok(relative) => relative.to_owned(),
+                Err(..) => bail!("{} is '{}', which is outside of the home directory, in path mapping '{}'",
+                                 head, value, to),
+            },
+            _ => PathBuf::from(default),
+        }
+    } else {
+        return self::relative_destination(PathBuf::from(to), to);
+    };
+
+    self::relative_destination(if tail.is_empty() { base } else { base.join(tail) }, to)
+}
+
+/// Checks that an expanded destination stays inside the home directory.
+fn relative_destination(destination: PathBuf, to: &str) -> Result<PathBuf, Error> {
+    // Anything else could escape the directory dotfiles are being linked into.
+    if !destination.components().all(|component| matches!(component, Component::Normal(..) | Component::CurDir)) {
+        bail!("path mapping '{}' leads outside of the home directory", to);
+    }
+
+    Ok(destination)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rule(from: &str, to: &str) -> Rule {
+        Rule { from: from.to_owned(), to: to.to_owned() }
+    }
+
+    fn map(mapper: &Mapper, path: &str) -> String {
+        mapper.map(Path::new(path)).to_str().unwrap().to_owned()
+    }
+
+    fn mapper(rules: &[Rule], dot_prefix: bool) -> Mapper {
+        Mapper::with_vars(rules, dot_prefix, Path::new("/home/jenny"), |name| match name {
+            "XDG_DATA_HOME" => Some("/home/jenny/.data".to_owned()),
+            "XDG_STATE_HOME" => Some("/var/state".to_owned()),
+            _ => None,
+        }).unwrap()
+    }
+
+    #[test]
+    fn paths_are_unchanged_by_default() {
+        let mapper = mapper(&[], false);
+
+        assert_eq!(map(&mapper, ".bashrc"), ".bashrc");
+        assert_eq!(map(&mapper, "dot-bashrc"), "dot-bashrc");
+        assert_eq!(map(&mapper, ".config/awesome/config.lua"), ".config/awesome/config.lua");
+    }
+
+    #[test]
+    fn dot_prefix_is_replaced() {
+        let mapper = mapper(&[], true);
+
+        assert_eq!(map(&mapper, "dot-bashrc"), ".bashrc");
+        assert_eq!(map(&mapper, "dot-config/awesome/config.lua"), ".config/awesome/config.lua");
+        assert_eq!(map(&mapper, "foo/dot-bar"), "foo/.bar");
+        assert_eq!(map(&mapper, "not-dot-foo"), "not-dot-foo");
+    }
+
+    #[test]
+    fn rules_map_directories() {
+        let mapper = mapper(&[
+            rule("config/**", "$XDG_CONFIG_HOME"),
+            rule("bin/*", "~/.local/bin"),
+            rule("data", "$XDG_DATA_HOME/stuff"),
+            rule("gitconfig", ".gitconfig"),
+        ], true);
+
+        assert_eq!(map(&mapper, "config/awesome/config.lua"), ".config/awesome/config.lua");
+        assert_eq!(map(&mapper, "config/dot-foo"), ".config/.foo");
+        assert_eq!(map(&mapper, "bin/ls"), ".local/bin/ls");
+        assert_eq!(map(&mapper, "bin/sub/ls"), "bin/sub/ls");
+        assert_eq!(map(&mapper, "data/a"), ".data/stuff/a");
+        assert_eq!(map(&mapper, "gitconfig"), ".gitconfig");
+        assert_eq!(map(&mapper, "configuration"), "configuration");
+    }
+
+    #[test]
+    fn paths_can_be_unmapped() {
+        let mapper = mapper(&[
+            rule("config/**", "$XDG_CONFIG_HOME"),
+            rule("bin/*", "~/.local/bin"),
+        ], true);
+        let unmap = |path: &str| mapper.unmap(Path::new(path)).map(|p| p.to_str().unwrap().to_owned());
+
+        assert_eq!(unmap(".config/awesome/config.lua"), Some("config/awesome/config.lua".to_owned()));
+        assert_eq!(unmap(".local/bin/ls"), Some("bin/ls".to_owned()));
+        assert_eq!(unmap(".local/bin/sub/ls"), Some("dot-local/bin/sub/ls".to_owned()));
+        assert_eq!(unmap(".ssh/id_ed25519"), Some("dot-ssh/id_ed25519".to_owned()));
+        // `config/foo` would be mapped into `.config` instead.
+        assert_eq!(unmap("config/foo"), None);
+    }
+
+    #[test]
+    fn destinations_outside_of_home_are_rejected() {
+        let with_vars = |rules: &[Rule]| Mapper::with_vars(rules, false, Path::new("/home/jenny"), |name| match name {
+            "XDG_STATE_HOME" => Some("/var/state".to_owned()),
+            _ => None,
+        });
+
+        assert!(with_vars(&[rule("state", "$XDG_STATE_HOME")]).is_err());
+        assert!(with_vars(&[rule("etc", "/etc")]).is_err());
+        assert!(with_vars(&[rule("up", "~/../bob")]).is_err());
+        assert!(with_vars(&[rule("up", "$XDG_CONFIG_HOME/../../bob")]).is_err());
+        assert!(with_vars(&[rule("config", "$XDG_CONFIG_HOME/./nvim")]).is_ok());
+    }
+
+    #[test]
+    fn unknown_variables_are_rejected() {
+        let result = Mapper::with_vars(&[rule("foo", "$FOO/bar")], false, Path::new("/home/jenny"), |_| None);
+        assert!(result.is_err());
+    }
+}
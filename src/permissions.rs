@@ -0,0 +1,154 @@
+use {Error, ResultExt};
+
+use globset::{GlobBuilder, GlobMatcher};
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::fs;
+
+/// The permission bit that Git tracks.
+const EXECUTABLE: u32 = 0o100;
+
+/// A rule that gives paths in the repository specific permissions.
+///
+/// `path` is a glob matched against paths in the repository, such as
+/// `.ssh/config` or `.gnupg/**`. `file` is the mode given to matching files,
+/// and `directory` is the mode given to directories polk creates to hold them.
+/// Modes are written in octal, such as `"600"`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub path: String,
+    #[serde(default)]
+    pub file: Option<String>,
+    #[serde(default)]
+    pub directory: Option<String>,
+}
+
+/// The modes declared for paths in the repository.
+#[derive(Clone, Debug, Default)]
+pub struct Modes {
+    rules: Vec<CompiledRule>,
+}
+
+/// A rule with its glob and modes parsed.
+#[derive(Clone, Debug)]
+struct CompiledRule {
+    glob: GlobMatcher,
+    file: Option<u32>,
+    directory: Option<u32>,
+}
+
+/// A file with looser permissions than it was declared with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    pub path: PathBuf,
+    /// The mode the file currently has.
+    pub actual: u32,
+    /// The mode the file was declared with.
+    pub expected: u32,
+}
+
+impl Modes {
+    /// Compiles a set of rules.
+    pub fn new(rules: &[Rule]) -> Result<Self, Error> {
+        let rules = rules.iter().map(|rule| {
+            let glob = GlobBuilder::new(&rule.path).literal_separator(true).build().
+                chain_err(|| format!("invalid path '{}'", rule.path))?;
+
+            Ok(CompiledRule {
+                glob: glob.compile_matcher(),
+                file: rule.file.as_ref().map(|m| self::parse_mode(m)).transpose()?,
+                directory: rule.directory.as_ref().map(|m| self::parse_mode(m)).transpose()?,
+            })
+        }).collect::<Result<_, Error>>()?;
+
+        Ok(Modes { rules })
+    }
+
+    /// Gets the mode for a file in the repository.
+    ///
+    /// The first matching rule with a file mode wins.
+    pub fn file_mode(&self, repository_path: &Path) -> Option<u32> {
+        self.rules.iter().filter(|rule| rule.glob.is_match(repository_path)).find_map(|rule| rule.file)
+    }
+
+    /// Gets the mode for directories created to hold a file in the repository.
+    ///
+    /// The first matching rule with a directory mode wins.
+    pub fn directory_mode(&self, repository_path: &Path) -> Option<u32> {
+        self.rules.iter().filter(|rule| rule.glob.is_match(repository_path)).find_map(|rule| rule.directory)
+    }
+}
+
+/// Parses an octal mode such as `600` or `0700`.
+fn parse_mode(mode: &str) -> Result<u32, Error> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => bail!("invalid mode '{}', expected an octal mode such as '600'", mode),
+    }
+}
+
+/// Works out the mode a file in the dotfiles repository should be given.
+///
+/// Git records whether files are executable by their owner, so that bit is
+/// kept as it is to stop the worktree from becoming dirty.
+pub fn tracked_mode(path: &Path, mode: u32) -> Result<u32, Error> {
+    let executable = fs::metadata(path)?.permissions().mode() & EXECUTABLE;
+    Ok((mode & !EXECUTABLE) | executable)
+}
+
+/// Sets the mode of a path, if it is not already set.
+pub fn apply(path: &Path, mode: u32) -> Result<(), Error> {
+    let metadata = fs::metadata(path)?;
+
+    if metadata.permissions().mode() & 0o7777 != mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).
+            chain_err(|| format!("could not set permissions of '{}'", path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Checks that a path is not accessible by anybody the mode does not allow.
+pub fn check(path: &Path, expected: u32) -> Result<Option<Violation>, Error> {
+    let actual = fs::metadata(path)?.permissions().mode() & 0o7777;
+
+    if actual & !expected != 0 {
+        Ok(Some(Violation { path: path.to_owned(), actual, expected }))
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rule(path: &str, file: Option<&str>, directory: Option<&str>) -> Rule {
+        Rule { path: path.to_owned(), file: file.map(ToOwned::to_owned), directory: directory.map(ToOwned::to_owned) }
+    }
+
+    #[test]
+    fn modes_are_matched_by_glob() {
+        let modes = Modes::new(&[
+            rule(".ssh/config", Some("600"), None),
+            rule(".ssh/**", Some("644"), Some("700")),
+            rule("*.key", Some("0400"), None),
+        ]).unwrap();
+
+        assert_eq!(modes.file_mode(Path::new(".ssh/config")), Some(0o600));
+        assert_eq!(modes.file_mode(Path::new(".ssh/known_hosts")), Some(0o644));
+        assert_eq!(modes.directory_mode(Path::new(".ssh/config")), Some(0o700));
+        assert_eq!(modes.file_mode(Path::new("a.key")), Some(0o400));
+        assert_eq!(modes.file_mode(Path::new("foo/a.key")), None);
+        assert_eq!(modes.file_mode(Path::new(".bashrc")), None);
+    }
+
+    #[test]
+    fn invalid_modes_are_rejected() {
+        assert!(Modes::new(&[rule("foo", Some("rw-------"), None)]).is_err());
+        assert!(Modes::new(&[rule("foo", None, Some("800"))]).is_err());
+        assert!(Modes::new(&[rule("foo", Some("17777"), None)]).is_err());
+    }
+}
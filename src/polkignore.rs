@@ -0,0 +1,142 @@
+//! Gitignore-style `.polkignore` files, which keep paths in a dotfiles
+//! repository from being linked.
+//!
+//! Every directory can have a `.polkignore`, using the same syntax as a
+//! `.gitignore`: patterns are relative to the directory the file is in, a
+//! trailing `/` only matches directories, and a leading `!` re-includes
+//! something an earlier pattern or a parent directory's file ignored.
+
+use {Error, ResultExt};
+
+use ignore::Match;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use walkdir::DirEntry;
+
+use std::path::Path;
+
+/// The name of the ignore file inside a directory of the repository.
+pub const IGNORE_FILE_NAME: &str = ".polkignore";
+
+/// Patterns which are ignored unless a `.polkignore` re-includes them.
+///
+/// These only match at the root, so that a `README.md` inside a program's
+/// configuration directory is still linked.
+pub const DEFAULT_PATTERNS: &[&str] = &[
+    "/README", "/README.*",
+    "/LICENSE", "/LICENSE.*",
+    "/HACKING", "/HACKING.*",
+    "/CONTRIBUTING", "/CONTRIBUTING.*",
+];
+
+/// The ignore files that apply during a walk of the dotfiles root.
+///
+/// Entries must be checked in the order `WalkDir` yields them, so that the
+/// files of directories the walk has left can be dropped.
+pub struct Ignores {
+    /// The default patterns, which apply from the root.
+    defaults: Gitignore,
+    /// The ignore files of the directories being walked, along with their depth.
+    files: Vec<(usize, Gitignore)>,
+}
+
+impl Ignores {
+    /// Creates the ignores for a walk starting at the dotfiles root.
+    pub fn new(root_path: &Path) -> Result<Self, Error> {
+        let mut builder = GitignoreBuilder::new(root_path);
+
+        for pattern in DEFAULT_PATTERNS {
+            builder.add_line(None, pattern).chain_err(|| format!("invalid default ignore pattern '{}'", pattern))?;
+        }
+
+        Ok(Ignores {
+            defaults: builder.build().chain_err(|| "could not build the default ignore patterns")?,
+            files: Vec::new(),
+        })
+    }
+
+    /// Checks if an entry of the walk is ignored.
+    ///
+    /// The `.polkignore` inside a directory that is not ignored is read so
+    /// that it applies to everything the walk finds inside it.
+    pub fn is_ignored(&mut self, entry: &DirEntry) -> Result<bool, Error> {
+        let depth = entry.depth();
+        let is_dir = entry.file_type().is_dir();
+
+        // Ignore files only apply below the directory they are in.
+        while self.files.last().is_some_and(|&(file_depth, _)| file_depth >= depth) {
+            self.files.pop();
+        }
+
+        let matchers = self.files.iter().rev().map(|(_, file)| file).chain(Some(&self.defaults));
+
+        if depth > 0 && self::is_match(matchers, entry.path(), is_dir) {
+            return Ok(true);
+        }
+
+        if is_dir {
+            let path = entry.path().join(IGNORE_FILE_NAME);
+
+            if path.is_file() {
+                let mut builder = GitignoreBuilder::new(entry.path());
+
+                if let Some(e) = builder.add(&path) {
+                    return Err(e).chain_err(|| format!("could not read '{}'", path.display()));
+                }
+
+                let file = builder.build().chain_err(|| format!("invalid pattern in '{}'", path.display()))?;
+                self.files.push((depth, file));
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+/// Checks if a path is ignored by a list of ignore files, the most specific first.
+///
+/// The first file with a pattern that matches decides, so a deeper file can
+/// re-include something that a shallower file ignores.
+fn is_match<'a, I>(matchers: I, path: &Path, is_dir: bool) -> bool
+    where I: IntoIterator<Item=&'a Gitignore> {
+    for matcher in matchers {
+        match matcher.matched(path, is_dir) {
+            Match::None => continue,
+            Match::Ignore(..) => return true,
+            Match::Whitelist(..) => return false,
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn gitignore(root: &str, lines: &[&str]) -> Gitignore {
+        let mut builder = GitignoreBuilder::new(root);
+        for line in lines {
+            builder.add_line(None, line).unwrap();
+        }
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn defaults_only_match_at_the_root() {
+        let defaults = gitignore("/dotfiles", DEFAULT_PATTERNS);
+
+        assert!(is_match(vec![&defaults], Path::new("/dotfiles/README.md"), false));
+        assert!(!is_match(vec![&defaults], Path::new("/dotfiles/.config/foo/README.md"), false));
+    }
+
+    #[test]
+    fn deeper_files_can_re_include_paths() {
+        let root = gitignore("/dotfiles", &["*.log", "build/"]);
+        let nested = gitignore("/dotfiles/.config", &["!keep.log"]);
+
+        assert!(is_match(vec![&nested, &root], Path::new("/dotfiles/.config/other.log"), false));
+        assert!(!is_match(vec![&nested, &root], Path::new("/dotfiles/.config/keep.log"), false));
+        assert!(is_match(vec![&root], Path::new("/dotfiles/build"), true));
+        assert!(!is_match(vec![&root], Path::new("/dotfiles/build"), false));
+    }
+}
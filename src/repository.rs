@@ -0,0 +1,259 @@
+use {Environment, Error, ResultExt};
+use mapping::{self, Mapper};
+use permissions::{self, Modes};
+use symlink;
+
+use globset::{Glob, GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use toml;
+
+use std::io::prelude::*;
+use std::path::{Component, Path, PathBuf};
+use std::fs;
+
+/// The name of the configuration file at the root of a dotfiles repository.
+pub const CONFIG_FILE_NAME: &str = ".polk.toml";
+
+/// Optional configuration stored inside a dotfiles repository.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Whether a leading `dot-` on file and directory names should be replaced with `.`.
+    #[serde(default)]
+    pub dot_prefix: bool,
+    /// Rules mapping paths in the repository to paths in the home directory.
+    #[serde(default, rename = "map")]
+    pub mappings: Vec<mapping::Rule>,
+    /// Rules giving paths in the repository specific permissions.
+    #[serde(default, rename = "mode")]
+    pub modes: Vec<permissions::Rule>,
+    /// Globs matching paths in the repository which are not dotfiles.
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    /// The subdirectory of the repository that holds the dotfiles.
+    ///
+    /// Every other path in the configuration is relative to it.
+    #[serde(default)]
+    pub root: Option<PathBuf>,
+    /// Rules deploying paths in the repository a specific way.
+    #[serde(default, rename = "link")]
+    pub link_modes: Vec<LinkRule>,
+    /// Commands to run around linking and updating.
+    #[serde(default)]
+    pub hooks: Hooks,
+    /// The oldest version of polk that understands the repository.
+    #[serde(default)]
+    pub min_polk_version: Option<String>,
+    /// Whether files the backend does not manage, such as files Git does
+    /// not track, are dotfiles too.
+    #[serde(default)]
+    pub include_untracked: bool,
+}
+
+/// A rule that deploys paths in the repository a specific way.
+///
+/// `path` is a glob, like the paths of mode rules.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LinkRule {
+    pub path: String,
+    pub mode: symlink::Mode,
+}
+
+/// Shell commands to run around linking and updating.
+///
+/// Hooks are run with `sh -c` from the root of the dotfiles, with `$HOME`
+/// set to the directory the dotfiles are linked into.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    /// Runs before any links are created. Linking stops if it fails.
+    pub pre_link: Option<String>,
+    /// Runs after every link has been created.
+    pub post_link: Option<String>,
+    /// Runs after the dotfiles have been updated.
+    pub post_update: Option<String>,
+}
+
+/// The paths in the repository which are not dotfiles.
+#[derive(Clone, Debug)]
+pub struct Ignored {
+    globs: GlobSet,
+}
+
+/// The ways paths in the repository are deployed.
+#[derive(Clone, Debug, Default)]
+pub struct LinkModes {
+    rules: Vec<(GlobMatcher, symlink::Mode)>,
+}
+
+impl Config {
+    /// Loads the configuration from the root of a repository.
+    ///
+    /// Returns the default configuration if the repository does not have any.
+    pub fn load(repository_path: &Path) -> Result<Self, Error> {
+        let path = repository_path.join(CONFIG_FILE_NAME);
+
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+
+        let mut file = fs::File::open(&path)?;
+        let mut config_toml = String::new();
+        file.read_to_string(&mut config_toml)?;
+
+        Config::parse(&config_toml, env!("CARGO_PKG_VERSION")).
+            chain_err(|| format!("cannot use the dotfiles in '{}'", repository_path.display()))
+    }
+
+    /// Parses a configuration for a version of polk.
+    ///
+    /// The minimum version is checked before anything else, so that a
+    /// configuration using settings from a newer polk asks for an upgrade
+    /// rather than failing to parse.
+    fn parse(config_toml: &str, polk_version: &str) -> Result<Self, Error> {
+        let table: toml::Table = toml::from_str(config_toml).chain_err(|| format!("could not parse {}", CONFIG_FILE_NAME))?;
+
+        match table.get("min_polk_version") {
+            Some(toml::Value::String(min_polk_version)) => self::check_version(min_polk_version, polk_version)?,
+            Some(..) => bail!("min_polk_version in {} must be a string", CONFIG_FILE_NAME),
+            None => (),
+        }
+
+        toml::Value::Table(table).try_into().chain_err(|| format!("could not parse {}", CONFIG_FILE_NAME))
+    }
+
+    /// Gets the directory holding the dotfiles inside a repository.
+    pub fn root_path(&self, repository_path: &Path) -> Result<PathBuf, Error> {
+        let root = match self.root {
+            Some(ref root) => root,
+            None => return Ok(repository_path.to_owned()),
+        };
+
+        if !root.components().all(|c| matches!(c, Component::Normal(..) | Component::CurDir)) {
+            bail!("the root '{}' in {} must be a subdirectory of the repository", root.display(), CONFIG_FILE_NAME);
+        }
+
+        let root_path = repository_path.join(root);
+
+        if !root_path.is_dir() {
+            bail!("the root '{}' in {} is not a directory in the repository", root.display(), CONFIG_FILE_NAME);
+        }
+
+        Ok(root_path)
+    }
+
+    /// Builds the mapper from repository paths to home directory paths.
+    pub fn mapper(&self, environment: &Environment) -> Result<Mapper, Error> {
+        Mapper::new(&self.mappings, self.dot_prefix, environment).
+            chain_err(|| format!("invalid path mapping in {}", CONFIG_FILE_NAME))
+    }
+
+    /// Builds the permissions declared for paths in the repository.
+    pub fn modes(&self) -> Result<Modes, Error> {
+        Modes::new(&self.modes).chain_err(|| format!("invalid mode in {}", CONFIG_FILE_NAME))
+    }
+
+    /// Builds the set of ignored paths in the repository.
+    pub fn ignored(&self) -> Result<Ignored, Error> {
+        let mut globs = GlobSetBuilder::new();
+
+        for pattern in self.ignore.iter() {
+            globs.add(self::glob(pattern).chain_err(|| format!("invalid ignore pattern in {}", CONFIG_FILE_NAME))?);
+        }
+
+        Ok(Ignored { globs: globs.build().chain_err(|| format!("invalid ignore pattern in {}", CONFIG_FILE_NAME))? })
+    }
+
+    /// Builds the ways paths in the repository are deployed.
+    pub fn link_modes(&self) -> Result<LinkModes, Error> {
+        let rules = self.link_modes.iter().map(|rule| {
+            let glob = self::glob(&rule.path).chain_err(|| format!("invalid link rule in {}", CONFIG_FILE_NAME))?;
+            Ok((glob.compile_matcher(), rule.mode))
+        }).collect::<Result<_, Error>>()?;
+
+        Ok(LinkModes { rules })
+    }
+}
+
+impl Ignored {
+    /// Checks if a path relative to the dotfiles root is ignored.
+    ///
+    /// Everything inside an ignored directory is ignored too.
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        path.ancestors().any(|ancestor| !ancestor.as_os_str().is_empty() && self.globs.is_match(ancestor))
+    }
+}
+
+impl LinkModes {
+    /// Gets the way a path relative to the dotfiles root should be deployed.
+    ///
+    /// The first matching rule wins.
+    pub fn mode(&self, path: &Path) -> Option<symlink::Mode> {
+        self.rules.iter().find(|(glob, _)| glob.is_match(path)).map(|&(_, mode)| mode)
+    }
+}
+
+/// Builds a glob that matches paths relative to the dotfiles root.
+fn glob(pattern: &str) -> Result<Glob, Error> {
+    GlobBuilder::new(pattern).literal_separator(true).build().chain_err(|| format!("invalid path '{}'", pattern))
+}
+
+/// Checks that a version of polk is at least the required version.
+fn check_version(required: &str, current: &str) -> Result<(), Error> {
+    let parse = |version: &str| -> Result<Vec<u64>, Error> {
+        version.split('.').map(|part| part.parse().chain_err(|| format!("invalid version '{}'", version))).collect()
+    };
+
+    if parse(current)? < parse(required)? {
+        bail!("the dotfiles need polk {} or newer, but this is polk {}", required, current);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(config_toml: &str) -> Config {
+        toml::from_str(config_toml).unwrap()
+    }
+
+    #[test]
+    fn ignored_directories_ignore_everything_inside() {
+        let ignored = config("ignore = [\"docs\", \"*.swp\"]").ignored().unwrap();
+
+        assert!(ignored.is_ignored(Path::new("docs")));
+        assert!(ignored.is_ignored(Path::new("docs/setup/README.md")));
+        assert!(ignored.is_ignored(Path::new(".bashrc.swp")));
+        assert!(!ignored.is_ignored(Path::new(".config/docs.conf")));
+        assert!(!ignored.is_ignored(Path::new(".bashrc")));
+    }
+
+    #[test]
+    fn link_modes_are_matched_by_glob() {
+        let link_modes = config("[[link]]\npath = \".ssh/*\"\nmode = \"copy\"").link_modes().unwrap();
+
+        assert_eq!(link_modes.mode(Path::new(".ssh/config")), Some(symlink::Mode::Copy));
+        assert_eq!(link_modes.mode(Path::new(".bashrc")), None);
+    }
+
+    #[test]
+    fn versions_are_compared_numerically() {
+        assert!(check_version("1.1", "1.1.4").is_ok());
+        assert!(check_version("1.1.10", "1.1.4").is_err());
+        assert!(check_version("2", "1.10.0").is_err());
+        assert!(check_version("1.x", "1.1.4").is_err());
+    }
+
+    #[test]
+    fn newer_versions_are_required_before_parsing() {
+        let config_toml = "min_polk_version = \"2.0\"\nsome_new_setting = true";
+
+        let error = Config::parse(config_toml, "1.1.4").unwrap_err();
+        assert!(error.to_string().contains("need polk 2.0 or newer"), "unexpected error: {}", error);
+
+        assert!(Config::parse(config_toml, "2.0.0").is_err(), "unknown settings should still be rejected");
+        assert_eq!(Config::parse("min_polk_version = \"1.0\"", "1.1.4").unwrap().min_polk_version.as_deref(), Some("1.0"));
+    }
+}
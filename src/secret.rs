@@ -0,0 +1,126 @@
+use {Dotfile, Error, ResultExt};
+use {symlink, util};
+use generated::{self, Generated};
+use journal::Journal;
+
+use age;
+use age::secrecy::ExposeSecret;
+
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::str::FromStr;
+use std::fs;
+
+/// The extension given to encrypted dotfiles.
+pub const EXTENSION: &str = "age";
+
+/// The permissions that decrypted dotfiles are given by default.
+pub const PERMISSIONS: u32 = 0o600;
+
+/// A key that can encrypt and decrypt secret dotfiles.
+pub struct Identity {
+    key: age::x25519::Identity,
+}
+
+impl Identity {
+    /// Loads an identity from an age identity file.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let mut identity_text = String::new();
+        fs::File::open(path).chain_err(|| format!("could not open identity '{}'", path.display()))?.
+            read_to_string(&mut identity_text)?;
+
+        // Identity files can have comments and blank lines.
+        let key_line = identity_text.lines().map(str::trim).find(|line| !line.is_empty() && !line.starts_with('#'));
+
+        match key_line.map(age::x25519::Identity::from_str) {
+            Some(Ok(key)) => Ok(Identity { key }),
+            Some(Err(e)) => bail!("invalid identity in '{}': {}", path.display(), e),
+            None => bail!("no identity found in '{}'", path.display()),
+        }
+    }
+
+    /// Generates a new identity and saves it to disk.
+    ///
+    /// The identity file is only readable by the current user.
+    pub fn generate(path: &Path) -> Result<Self, Error> {
+        let key = age::x25519::Identity::generate();
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(PERMISSIONS).open(path).
+            chain_err(|| format!("could not create identity '{}'", path.display()))?;
+        writeln!(file, "# public key: {}", key.to_public())?;
+        writeln!(file, "{}", key.to_string().expose_secret())?;
+
+        Ok(Identity { key })
+    }
+
+    /// Encrypts some data so that only this identity can decrypt it.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        age::encrypt(&self.key.to_public(), plaintext).chain_err(|| "could not encrypt data")
+    }
+
+    /// Decrypts some data.
+    pub fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        age::decrypt(&self.key, ciphertext).chain_err(|| "could not decrypt data")
+    }
+
+    /// Decrypts an encrypted file.
+    pub fn decrypt_file(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        let ciphertext = fs::read(path)?;
+        self.decrypt(&ciphertext).chain_err(|| format!("could not decrypt '{}'", path.display()))
+    }
+}
+
+/// Checks if a path looks like an encrypted dotfile.
+pub fn is_secret(path: &Path) -> bool {
+    path.extension().map(|e| e == EXTENSION).unwrap_or(false)
+}
+
+/// Decrypts a secret dotfile into place.
+///
+/// The plaintext is only ever written to the destination, which must be
+/// outside of the dotfiles repository so that it cannot be committed.
+pub fn deploy(dotfile: &Dotfile,
+              config: &symlink::Config,
+              identity: &Identity,
+              repository_path: &Path,
+              permissions: Option<u32>,
+              previous_checksum: Option<&str>,
+              journal: &mut Journal) -> Result<Generated, Error> {
+    let dest_path = symlink::path(dotfile, config);
+
+    if util::is_inside(&dest_path, repository_path)? {
+        bail!("refusing to decrypt '{}' into the dotfiles repository at '{}'",
+              dotfile.full_path.display(), dest_path.display());
+    }
+
+    let plaintext = identity.decrypt_file(&dotfile.full_path)?;
+
+    generated::write(dotfile, config, &plaintext, Some(permissions.unwrap_or(PERMISSIONS)), previous_checksum, journal)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testing::temp_directory;
+
+    #[test]
+    fn encrypted_data_can_be_decrypted() {
+        let dir = temp_directory("secret");
+        let path = dir.join("identity.txt");
+
+        let identity = Identity::generate(&path).unwrap();
+        let ciphertext = identity.encrypt(b"hunter2").unwrap();
+        assert!(!ciphertext.windows(7).any(|window| window == b"hunter2"), "ciphertext should not contain the plaintext");
+
+        // A reloaded identity must be able to decrypt what the original encrypted.
+        let identity = Identity::load(&path).unwrap();
+        assert_eq!(identity.decrypt(&ciphertext).unwrap(), b"hunter2");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
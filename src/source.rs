@@ -5,10 +5,10 @@ use std::fmt::{self, Write};
 use std::str::FromStr;
 
 /// The full URL to GitHub.
-pub const GITHUB_URL: &'static str = "https://github.com";
+pub const GITHUB_URL: &str = "https://github.com";
 
 /// The assumed name of a repository containing dotfiles.
-pub const DEFAULT_GIT_REPOSITORY_NAME: &'static str = "dotfiles";
+pub const DEFAULT_GIT_REPOSITORY_NAME: &str = "dotfiles";
 
 mod spec_matchers {
     use regex::Regex;
@@ -53,7 +53,7 @@ impl SourceSpec
                     unwrap_or(DEFAULT_GIT_REPOSITORY_NAME.to_owned());
 
                 let url = format!("{}/{}/{}.git", GITHUB_URL, username, repository);
-                Source::Git { url: url }
+                Source::Git { url }
             },
             SourceSpec::Url(ref url) => {
                 Source::Git { url: url.clone() }
@@ -112,7 +112,7 @@ impl FromStr for SourceSpec {
             let username = captures.get(1).unwrap().as_str().to_owned();
             let repository = captures.get(2).map(|m| m.as_str().to_owned());
 
-            Ok(SourceSpec::GitHub { username: username, repository: repository })
+            Ok(SourceSpec::GitHub { username, repository })
         } else {
             // Assume URL if nothing else.
             Ok(SourceSpec::Url(s.to_owned()))
@@ -141,9 +141,9 @@ impl<'de> serde::Deserialize<'de> for SourceSpec {
     }
 }
 
-impl Into<Source> for SourceSpec {
-    fn into(self) -> Source {
-        self.canonical()
+impl From<SourceSpec> for Source {
+    fn from(spec: SourceSpec) -> Source {
+        spec.canonical()
     }
 }
 
@@ -0,0 +1,199 @@
+use {Dotfile, DotfileKind, Error};
+use {ledger, symlink, util};
+
+use std::path::PathBuf;
+use std::{fs, io};
+
+/// The state of a single dotfile in the home directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    /// The dotfile is deployed where it should be.
+    Linked,
+    /// Nothing is at the destination.
+    Missing,
+    /// The destination is a symlink to somewhere else.
+    Elsewhere(PathBuf),
+    /// A file or directory that polk did not create is in the way.
+    Blocked { directory: bool },
+    /// A symlink polk created was replaced with a regular file, usually by
+    /// an application saving its configuration.
+    Replaced,
+    /// The destination is a symlink to something that does not exist.
+    Dangling(PathBuf),
+    /// The dotfile requires features this machine does not have.
+    Excluded,
+    /// A generated file has been modified since polk wrote it.
+    Drifted,
+}
+
+/// The state of a dotfile, along with where it is deployed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DotfileStatus {
+    /// The path in the dotfiles repository.
+    pub source: PathBuf,
+    /// The path in the home directory.
+    pub destination: PathBuf,
+    pub state: State,
+}
+
+impl State {
+    /// Checks if nothing needs to be done about the dotfile.
+    pub fn is_ok(&self) -> bool {
+        matches!(*self, State::Linked | State::Excluded)
+    }
+
+    /// A short description of the state.
+    pub fn label(&self) -> &'static str {
+        match *self {
+            State::Linked => "linked",
+            State::Missing => "missing",
+            State::Elsewhere(..) => "elsewhere",
+            State::Blocked { .. } => "blocked",
+            State::Replaced => "replaced",
+            State::Dangling(..) => "dangling",
+            State::Excluded => "excluded",
+            State::Drifted => "drifted",
+        }
+    }
+}
+
+/// Works out the state of a dotfile that is supported by this machine.
+///
+/// `entry` is the ledger's record of the destination, if there is one.
+pub fn classify(dotfile: &Dotfile,
+                config: &symlink::Config,
+                entry: Option<&ledger::Entry>) -> Result<State, Error> {
+    let destination = symlink::path(dotfile, config);
+
+    let metadata = match fs::symlink_metadata(&destination) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound || e.kind() == io::ErrorKind::NotADirectory => {
+            return Ok(State::Missing);
+        },
+        Err(e) => return Err(e.into()),
+    };
+
+    let is_generated = dotfile.kind == DotfileKind::Template || dotfile.kind == DotfileKind::Secret;
+    // Generated files are ours as long as the ledger says we wrote them.
+    let generated_entry = entry.filter(|e| e.mode != ledger::Mode::Symlink && e.target == dotfile.full_path);
+
+    if metadata.file_type().is_symlink() {
+        let target = fs::read_link(&destination)?;
+
+        if !is_generated && target == dotfile.full_path {
+            Ok(State::Linked)
+        } else if !destination.exists() {
+            Ok(State::Dangling(target))
+        } else {
+            Ok(State::Elsewhere(target))
+        }
+    } else if metadata.is_dir() || (!is_generated && generated_entry.is_none()) {
+        let was_linked = entry.map(|e| e.mode == ledger::Mode::Symlink && e.target == dotfile.full_path).unwrap_or(false);
+
+        if was_linked && metadata.is_file() {
+            Ok(State::Replaced)
+        } else {
+            Ok(State::Blocked { directory: metadata.is_dir() })
+        }
+    } else {
+        match generated_entry {
+            Some(entry) if entry.checksum == Some(util::checksum_file(&destination)?) => Ok(State::Linked),
+            Some(..) => Ok(State::Drifted),
+            None => Ok(State::Blocked { directory: false }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testing::temp_directory;
+
+    use std::os::unix;
+
+    /// Runs a function with a dotfile in a fresh repository and an empty home directory.
+    fn with_dotfile<F>(f: F)
+        where F: FnOnce(&Dotfile, &symlink::Config) {
+        let root = temp_directory("status");
+        fs::create_dir_all(root.join("repo")).unwrap();
+        fs::create_dir_all(root.join("home")).unwrap();
+        fs::write(root.join("repo/.vimrc"), "set ruler").unwrap();
+
+        let dotfile = Dotfile {
+            full_path: root.join("repo/.vimrc"),
+            relative_path: PathBuf::from(".vimrc"),
+            kind: DotfileKind::File,
+        };
+        f(&dotfile, &symlink::Config::with_home(root.join("home")));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn link_entry(dotfile: &Dotfile, config: &symlink::Config) -> ledger::Entry {
+        ledger::Entry {
+            path: symlink::path(dotfile, config),
+            target: dotfile.full_path.clone(),
+            user: "jenny".to_owned(),
+            home: config.home_path.clone(),
+            revision: None,
+            mode: ledger::Mode::Symlink,
+            checksum: None,
+        }
+    }
+
+    #[test]
+    fn linked_and_missing_dotfiles_are_recognised() {
+        with_dotfile(|dotfile, config| {
+            assert_eq!(classify(dotfile, config, None).unwrap(), State::Missing);
+
+            unix::fs::symlink(&dotfile.full_path, symlink::path(dotfile, config)).unwrap();
+            assert_eq!(classify(dotfile, config, None).unwrap(), State::Linked);
+        });
+    }
+
+    #[test]
+    fn links_elsewhere_are_recognised() {
+        with_dotfile(|dotfile, config| {
+            unix::fs::symlink("/nonexistent", symlink::path(dotfile, config)).unwrap();
+            assert_eq!(classify(dotfile, config, None).unwrap(), State::Dangling(PathBuf::from("/nonexistent")));
+
+            fs::remove_file(symlink::path(dotfile, config)).unwrap();
+            unix::fs::symlink(&config.home_path, symlink::path(dotfile, config)).unwrap();
+            assert_eq!(classify(dotfile, config, None).unwrap(), State::Elsewhere(config.home_path.clone()));
+        });
+    }
+
+    #[test]
+    fn files_in_the_way_are_recognised() {
+        with_dotfile(|dotfile, config| {
+            fs::write(symlink::path(dotfile, config), "set number").unwrap();
+            assert_eq!(classify(dotfile, config, None).unwrap(), State::Blocked { directory: false });
+
+            // Only a file polk had linked counts as replaced.
+            let entry = link_entry(dotfile, config);
+            assert_eq!(classify(dotfile, config, Some(&entry)).unwrap(), State::Replaced);
+
+            fs::remove_file(symlink::path(dotfile, config)).unwrap();
+            fs::create_dir(symlink::path(dotfile, config)).unwrap();
+            assert_eq!(classify(dotfile, config, Some(&entry)).unwrap(), State::Blocked { directory: true });
+        });
+    }
+
+    #[test]
+    fn generated_files_are_checked_against_the_ledger() {
+        with_dotfile(|dotfile, config| {
+            let dotfile = Dotfile { kind: DotfileKind::Template, ..dotfile.clone() };
+            let destination = symlink::path(&dotfile, config);
+            fs::write(&destination, "set ruler").unwrap();
+
+            let mut entry = link_entry(&dotfile, config);
+            entry.mode = ledger::Mode::Rendered;
+            entry.checksum = Some(util::checksum_file(&destination).unwrap());
+            assert_eq!(classify(&dotfile, config, Some(&entry)).unwrap(), State::Linked);
+
+            fs::write(&destination, "set number").unwrap();
+            assert_eq!(classify(&dotfile, config, Some(&entry)).unwrap(), State::Drifted);
+            assert_eq!(classify(&dotfile, config, None).unwrap(), State::Blocked { directory: false });
+        });
+    }
+}
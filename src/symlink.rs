@@ -1,70 +1,113 @@
-use {Dotfile, Error};
+use {Dotfile, Environment, Error, ErrorKind};
+use journal::Journal;
 use util;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::fs;
 use std::os::unix;
+use std::os::unix::fs::MetadataExt;
 
 /// Configuration for symlinking.
 #[derive(Debug)]
 pub struct Config {
     pub home_path: PathBuf,
+    /// How files are deployed.
+    pub mode: Mode,
+    /// What to do about something that is in the way of a dotfile.
+    pub conflicts: Conflicts,
+}
+
+/// How files are deployed into the home directory.
+///
+/// Directories, templates and secrets are deployed the same way regardless.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Files are symlinked into the dotfiles repository.
+    Symlink,
+    /// Files are copied, for programs that do not follow symlinks.
+    Copy,
+}
+
+/// What to do about an existing file or directory in the way of a dotfile.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Conflicts {
+    /// Leave it alone and do not deploy the dotfile.
+    Skip,
+    /// Move it next to where it was, with a `.polk-backup` suffix.
+    Backup,
+    /// Delete it.
+    Overwrite,
+    /// Stop, undoing everything deployed so far.
+    Fail,
+}
+
+/// A symlink that was created.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Link {
+    /// The path of the symlink.
+    pub path: PathBuf,
+    /// The directories that had to be created to hold the symlink, outermost first.
+    pub created_directories: Vec<PathBuf>,
 }
 
 /// Creates a symlink to a dotfile.
-pub fn build(dotfile: &Dotfile, config: &Config) -> Result<(), Error> {
+///
+/// Fails with `LinkConflict` if something else is already in the way of the
+/// symlink, including a symlink to somewhere else.
+pub fn build(dotfile: &Dotfile, config: &Config, journal: &mut Journal) -> Result<Link, Error> {
     let dest_path = self::path(dotfile, config);
+    journal.track(&dest_path)?;
+
+    // Look at the path itself rather than what it points to so that
+    // existing symlinks to directories are not mistaken for directories.
+    if let Ok(metadata) = fs::symlink_metadata(&dest_path) {
+        if metadata.file_type().is_symlink() {
+            // No harm in recreating a symlink to the same location, but
+            // anybody else's symlink is left to the conflict policy.
+            if !self::points_to(&dest_path, &dotfile.full_path)? {
+                bail!(ErrorKind::LinkConflict(dest_path, "symlink"));
+            }
 
-    if dest_path.exists() {
-        if dest_path.is_dir() {
-            warn!("there is an existing directory at '{}', will not create symlink", dest_path.display());
-            return Ok(());
+            fs::remove_file(&dest_path)?;
+        } else if metadata.is_dir() {
+            bail!(ErrorKind::LinkConflict(dest_path, "directory"));
         } else {
-            let metadata = fs::symlink_metadata(&dest_path)?;
-
-            if metadata.file_type().is_symlink() {
-                let current_target = fs::read_link(&dest_path)?;
-
-                if current_target.canonicalize()? == dotfile.full_path.canonicalize()? {
-                    // No harm in recreating a symlink to the same location.
-                    fs::remove_file(&dest_path)?;
-                } else {
-                    ilog!("there is an existing symlink to a different file at '{}', deleting it", dest_path.display());
-                }
-            } else {
-                warn!("there is an existing file at '{}', will not create symlink", dest_path.display());
-                return Ok(());
-            }
+            bail!(ErrorKind::LinkConflict(dest_path, "file"));
         }
     }
 
     // If the dotfile is in a subdirectory, we need to
     // create the subdirectory inside the home directory
     // for the symlink to live in.
-    if let Some(parent) = dest_path.parent() {
-        if !parent.exists() {
-            fs::create_dir_all(parent)?;
-        }
-    }
-
-    println!("{} -> {}", dotfile.full_path.display(), dest_path.display());
+    let created_directories = match dest_path.parent() {
+        Some(parent) => util::create_directories(parent)?,
+        None => Vec::new(),
+    };
+
+    progress!("{} -> {}", dotfile.full_path.display(), dest_path.display());
+    // Unix symlinks are the same regardless of whether they point
+    // to a file or to a directory.
     unix::fs::symlink(&dotfile.full_path, &dest_path)?;
 
-    Ok(())
+    Ok(Link { path: dest_path, created_directories })
 }
 
-/// Destroys the symlink to a dotfile.
-pub fn destroy(dotfile: &Dotfile, config: &Config) -> Result<(), Error> {
-    use std::io::ErrorKind::NotFound;
-
-    let dest_path = self::path(dotfile, config);
-    println!("destroying dotfile: {}", dest_path.display());
+/// Checks if a symlink points to a file.
+///
+/// Polk links to the full path of a dotfile, so the symlink only needs
+/// resolving if something else created it.
+pub fn points_to(symlink_path: &Path, target: &Path) -> Result<bool, Error> {
+    Ok(fs::read_link(symlink_path)? == target || self::is_same_file(symlink_path, target))
+}
 
-    match fs::remove_file(&dest_path) {
-        Ok(..) => Ok(()),
-        // No point complaining if the symlink is already gone.
-        Err(ref e) if e.kind() == NotFound => Ok(()),
-        Err(e) => Err(e.into()),
+/// Checks if two paths lead to the same file once symlinks are followed.
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
     }
 }
 
@@ -83,11 +126,44 @@ pub fn path(dotfile: &Dotfile, config: &Config) -> PathBuf {
     config.home_path.join(&dotfile.relative_path)
 }
 
-impl Default for Config
-{
-    fn default() -> Config {
+impl Config {
+    /// Creates symlinks in the home directory of an environment.
+    pub fn new(environment: &Environment) -> Config {
+        Config::with_home(environment.home_path.clone())
+    }
+
+    /// Creates symlinks in a directory, leaving anything in the way alone.
+    pub fn with_home(home_path: PathBuf) -> Config {
         Config {
-            home_path: util::home_dir(),
+            home_path,
+            mode: Mode::Symlink,
+            conflicts: Conflicts::Skip,
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "symlink" => Ok(Mode::Symlink),
+            "copy" => Ok(Mode::Copy),
+            _ => bail!("unknown link mode '{}', expected 'symlink' or 'copy'", s),
+        }
+    }
+}
+
+impl FromStr for Conflicts {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "skip" => Ok(Conflicts::Skip),
+            "backup" => Ok(Conflicts::Backup),
+            "overwrite" => Ok(Conflicts::Overwrite),
+            "fail" => Ok(Conflicts::Fail),
+            _ => bail!("unknown conflict policy '{}', expected 'skip', 'backup', 'overwrite' or 'fail'", s),
         }
     }
 }
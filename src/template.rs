@@ -0,0 +1,263 @@
+use {Dotfile, Environment, FeatureSet, Error, ResultExt};
+use {feature, symlink};
+use generated::{self, Generated};
+use journal::Journal;
+
+use hostname;
+use toml;
+
+use std::collections::BTreeMap;
+use std::io::prelude::*;
+use std::path::Path;
+use std::fs;
+
+/// The extension given to template dotfiles.
+pub const EXTENSION: &str = "tmpl";
+
+/// The values available to templates.
+///
+/// * `hostname` - the name of the machine
+/// * `user` - the user whose dotfiles are being rendered
+/// * `os`, `family`, `arch` - the enabled feature of each kind
+/// * `features.<name>` - set for every enabled feature
+/// * `env.<NAME>` - environment variables
+/// * `vars.<name>` - values from the user's local variables file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Context {
+    values: BTreeMap<String, String>,
+}
+
+/// The state of a conditional block.
+struct Condition {
+    /// Whether the current branch of the block is being output.
+    active: bool,
+    /// Whether we have already seen `{{else}}`.
+    seen_else: bool,
+}
+
+impl Context {
+    /// Builds the context for the current machine.
+    pub fn current(environment: &Environment,
+                   username: &str,
+                   features: &FeatureSet,
+                   vars_path: &Path) -> Result<Self, Error> {
+        let mut context = Context::default();
+
+        let hostname = hostname::get()?;
+        context.set("hostname", hostname.to_string_lossy());
+        context.set("user", username);
+
+        for &feature in features.enabled_features.iter() {
+            if let Some(kind) = feature::feature_kind(feature) {
+                context.set(kind, feature);
+            }
+
+            context.set(format!("features.{}", feature), "true");
+        }
+
+        for (name, value) in environment.vars.iter() {
+            context.set(format!("env.{}", name), value.to_string_lossy());
+        }
+
+        for (name, value) in self::load_vars(vars_path)? {
+            context.set(format!("vars.{}", name), value);
+        }
+
+        Ok(context)
+    }
+
+    /// Sets a value.
+    pub fn set<K, V>(&mut self, name: K, value: V) where K: Into<String>, V: Into<String> {
+        self.values.insert(name.into(), value.into());
+    }
+
+    /// Gets a value.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(|v| v.as_str())
+    }
+
+    /// Checks if a condition holds.
+    ///
+    /// Conditions are value names, optionally prefixed with `!`. A value is
+    /// true when it is set and is not empty, `false` or `0`.
+    fn is_true(&self, condition: &str) -> bool {
+        if let Some(condition) = condition.strip_prefix('!') {
+            return !self.is_true(condition.trim());
+        }
+
+        match self.get(condition) {
+            Some(value) => !value.is_empty() && value != "false" && value != "0",
+            None => false,
+        }
+    }
+}
+
+/// Checks if a path looks like a template.
+pub fn is_template(path: &Path) -> bool {
+    path.extension().map(|e| e == EXTENSION).unwrap_or(false)
+}
+
+/// Loads the user-defined variables from a TOML file.
+///
+/// The file is optional, and lives outside of the dotfiles repository
+/// so that it is never committed.
+pub fn load_vars(path: &Path) -> Result<BTreeMap<String, String>, Error> {
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+
+    let mut file = fs::File::open(path)?;
+    let mut vars_toml = String::new();
+    file.read_to_string(&mut vars_toml)?;
+
+    let table: toml::Table = toml::from_str(&vars_toml).
+        chain_err(|| format!("could not parse template variables '{}'", path.display()))?;
+
+    Ok(table.into_iter().map(|(name, value)| {
+        let value = match value {
+            toml::Value::String(s) => s,
+            other => other.to_string(),
+        };
+
+        (name, value)
+    }).collect())
+}
+
+/// Renders a template.
+pub fn render(template: &str, context: &Context) -> Result<String, Error> {
+    let mut output = String::new();
+    let mut conditions: Vec<Condition> = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let active = conditions.iter().all(|c| c.active);
+        let after_open = &rest[start + 2..];
+        let end = match after_open.find("}}") {
+            Some(end) => end,
+            None => bail!("unterminated '{{{{' in template"),
+        };
+        let tag = after_open[..end].trim();
+        let mut before = &rest[..start];
+        let mut after = &after_open[end + 2..];
+
+        let is_block = tag.starts_with('#') || tag.starts_with('/') || tag == "else";
+
+        // Block tags on a line of their own shouldn't leave blank lines behind.
+        if is_block {
+            let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+            let line_end = after.find('\n').map(|i| i + 1).unwrap_or(after.len());
+
+            if before[line_start..].trim().is_empty() && after[..line_end].trim().is_empty() {
+                before = &before[..line_start];
+                after = &after[line_end..];
+            }
+        }
+
+        if active {
+            output.push_str(before);
+        }
+
+        if let Some(condition) = tag.strip_prefix("#if ") {
+            conditions.push(Condition { active: context.is_true(condition.trim()), seen_else: false });
+        } else if tag == "else" {
+            match conditions.last_mut() {
+                Some(ref mut c) if !c.seen_else => {
+                    c.active = !c.active;
+                    c.seen_else = true;
+                },
+                _ => bail!("unexpected '{{{{else}}}}' in template"),
+            }
+        } else if tag == "/if" {
+            if conditions.pop().is_none() {
+                bail!("unexpected '{{{{/if}}}}' in template");
+            }
+        } else if is_block {
+            bail!("unknown template tag '{}'", tag);
+        } else if active {
+            match context.get(tag) {
+                Some(value) => output.push_str(value),
+                None => bail!("unknown template variable '{}'", tag),
+            }
+        }
+
+        rest = after;
+    }
+
+    if !conditions.is_empty() {
+        bail!("missing '{{{{/if}}}}' in template");
+    }
+
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Renders a template file.
+pub fn render_file(path: &Path, context: &Context) -> Result<String, Error> {
+    let mut template = String::new();
+    fs::File::open(path)?.read_to_string(&mut template)?;
+
+    self::render(&template, context).chain_err(|| format!("could not render template '{}'", path.display()))
+}
+
+/// Renders a template dotfile into place.
+///
+/// An existing file is only overwritten if it is a previous rendering with
+/// the given checksum. Fails with `LinkConflict` if something else is in the way.
+pub fn deploy(dotfile: &Dotfile,
+              config: &symlink::Config,
+              context: &Context,
+              permissions: Option<u32>,
+              previous_checksum: Option<&str>,
+              journal: &mut Journal) -> Result<Generated, Error> {
+    let contents = self::render_file(&dotfile.full_path, context)?;
+
+    generated::write(dotfile, config, contents.as_bytes(), permissions, previous_checksum, journal)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn context() -> Context {
+        let mut context = Context::default();
+        context.set("user", "jenny");
+        context.set("features.linux", "true");
+        context.set("vars.email", "jenny@example.com");
+        context.set("vars.work", "false");
+        context
+    }
+
+    fn render(template: &str) -> String {
+        super::render(template, &context()).unwrap()
+    }
+
+    #[test]
+    fn variables_are_substituted() {
+        assert_eq!(render("plain text"), "plain text");
+        assert_eq!(render("name = {{user}}\nemail = {{ vars.email }}\n"),
+                   "name = jenny\nemail = jenny@example.com\n");
+    }
+
+    #[test]
+    fn conditions_select_branches() {
+        assert_eq!(render("a{{#if features.linux}}b{{/if}}c"), "abc");
+        assert_eq!(render("a{{#if features.macos}}b{{else}}d{{/if}}c"), "adc");
+        assert_eq!(render("{{#if !vars.work}}home{{else}}work{{/if}}"), "home");
+        assert_eq!(render("{{#if features.linux}}{{#if vars.work}}x{{else}}y{{/if}}{{/if}}"), "y");
+    }
+
+    #[test]
+    fn standalone_block_lines_are_removed() {
+        assert_eq!(render("[user]\n  {{#if vars.email}}\n  email = {{vars.email}}\n  {{/if}}\nend\n"),
+                   "[user]\n  email = jenny@example.com\nend\n");
+    }
+
+    #[test]
+    fn errors_are_reported() {
+        assert!(super::render("{{nope}}", &context()).is_err());
+        assert!(super::render("{{user", &context()).is_err());
+        assert!(super::render("{{#if user}}", &context()).is_err());
+        assert!(super::render("{{/if}}", &context()).is_err());
+        assert!(super::render("{{else}}", &context()).is_err());
+    }
+}
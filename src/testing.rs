@@ -0,0 +1,28 @@
+//! Fixtures shared by the tests of every module.
+
+use {Cache, Environment};
+
+use rand::random;
+
+use std::path::PathBuf;
+use std::{env, fs};
+
+/// Creates an empty temporary directory, which the test deletes once it is done.
+pub fn temp_directory(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("polk-{}-{:016x}", name, random::<u64>()));
+    fs::create_dir_all(&path).unwrap();
+    path
+}
+
+/// Runs a function with a freshly-created cache in its own home directory.
+pub fn with_cache<F>(f: F)
+    where F: FnOnce(&mut Cache) {
+    let root = self::temp_directory("cache");
+    let mut environment = Environment::new(root.join("home"), "jenny");
+    environment.cache_path = root.join("cache");
+    fs::create_dir_all(&environment.home_path).unwrap();
+
+    f(&mut Cache::create(environment).unwrap());
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -1,7 +1,7 @@
-use {UserCache, Error, ResultExt};
+use {Environment, UserCache, Error, ResultExt};
 use symlink;
 
-use std::{fs, env};
+use std::fs;
 use std::process::Command;
 use std::os::unix::process::CommandExt;
 
@@ -20,18 +20,20 @@ pub struct Shell<'a> {
 
 impl<'a> Shell<'a> {
     /// Creates a new shell for the user.
-    pub fn create(user_cache: &'a mut UserCache<'a>, config: Config) -> Result<Self, Error> {
+    ///
+    /// See `lock::acquire` for `wait`.
+    pub fn create(user_cache: &'a mut UserCache<'a>, config: Config, wait: bool) -> Result<Self, Error> {
         let home_path = user_cache.home_path();
         if !home_path.exists() {
             fs::create_dir_all(&home_path)?;
         }
 
         let mut shell = Shell {
-            user_cache: user_cache,
-            config: config,
+            user_cache,
+            config,
         };
 
-        shell.build_symlinks()?;
+        shell.build_symlinks(wait)?;
 
         Ok(shell)
     }
@@ -40,7 +42,7 @@ impl<'a> Shell<'a> {
         let home_path = self.user_cache.home_path();
 
         let err: Error = Command::new(&self.config.shell_path)
-            .current_dir(&home_path.display().to_string())
+            .current_dir(home_path.display().to_string())
             .env("HOME", home_path.display().to_string())
             .exec()
             .into();
@@ -49,20 +51,19 @@ impl<'a> Shell<'a> {
     }
 
     /// Build all of the symlinks for the custom home directory.
-    fn build_symlinks(&mut self) -> Result<(), Error> {
+    fn build_symlinks(&mut self, wait: bool) -> Result<(), Error> {
         let verbose = false;
         let home_path = self.user_cache.home_path();
 
-        self.user_cache.link_ext(&symlink::Config {
-            home_path: home_path,
-        }, verbose)
+        self.user_cache.link_ext(&symlink::Config::with_home(home_path), wait, verbose)
     }
 }
 
-impl Default for Config {
-    fn default() -> Config {
+impl Config {
+    /// Runs the shell of an environment.
+    pub fn new(environment: &Environment) -> Config {
         Config {
-            shell_path: env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_owned()),
+            shell_path: environment.shell_path.clone(),
         }
     }
 }
@@ -1,25 +1,70 @@
-use std::path::PathBuf;
-use std::env;
-
-/// Gets the user's home directory.
-pub fn home_dir() -> PathBuf {
-    #[cfg(not(test))]
-    {
-        env::home_dir().expect("user does not have home directory")
+use sha2::{Digest, Sha256};
+
+use std::ffi::OsString;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+/// Creates a directory and all of its missing parents.
+///
+/// Returns the directories that did not already exist, outermost first.
+pub fn create_directories(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut missing: Vec<_> = path.ancestors().take_while(|p| !p.exists()).map(ToOwned::to_owned).collect();
+    missing.reverse();
+
+    for directory in missing.iter() {
+        fs::create_dir(directory)?;
     }
 
-    #[cfg(test)]
-    {
-        use rand::random;
+    Ok(missing)
+}
+
+/// Replaces a file's contents so that it is never left half-written.
+///
+/// The contents are written to a temporary file next to it, which is then
+/// renamed over the top of the file.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let file_name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let mut temporary_name = OsString::from(".");
+    temporary_name.push(file_name);
+    temporary_name.push(".tmp");
+    let temporary_path = path.with_file_name(temporary_name);
 
-        lazy_static! {
-            /// This is an example for using doc comment attributes
-            static ref FAKE_HOME_DIR: PathBuf = {
-                let (a,b): (u16,u16) = (random(), random());
-                env::temp_dir().join(format!("home-{}-{}", a,b))
-            };
-        }
+    let mut file = fs::File::create(&temporary_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+
+    fs::rename(&temporary_path, path)
+}
 
-        FAKE_HOME_DIR.clone()
+/// Computes the hex-encoded SHA-256 checksum of some data.
+pub fn checksum(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Computes the checksum of a file's contents.
+pub fn checksum_file(path: &Path) -> io::Result<String> {
+    fs::read(path).map(|data| self::checksum(&data))
+}
+
+/// Checks if a path is inside a directory once symlinks are resolved.
+///
+/// The path does not need to exist.
+pub fn is_inside(path: &Path, directory: &Path) -> io::Result<bool> {
+    let directory = match directory.canonicalize() {
+        Ok(directory) => directory,
+        // Nothing can be inside a directory that doesn't exist.
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+
+    // Resolve the deepest part of the path that exists.
+    for ancestor in path.ancestors() {
+        if let Ok(resolved) = ancestor.canonicalize() {
+            let rest = path.strip_prefix(ancestor).unwrap();
+            return Ok(resolved.join(rest).starts_with(&directory));
+        }
     }
+
+    Ok(path.starts_with(&directory))
 }